@@ -0,0 +1,186 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use alloc::boxed::Box;
+
+/// Whether a single bus access read or wrote.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+  Read,
+  Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+  Read,
+  Write,
+  ReadWrite,
+}
+
+/// A watched access. `kind` is `Read` or `Write`, whichever actually happened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+  pub addr: u16,
+  pub value: u8,
+  pub kind: WatchKind,
+}
+
+/// The CPU's view of the 16-bit address space. Object-safe, so a front-end
+/// can pick an implementation at runtime and hand the CPU a `&mut dyn Bus`.
+pub trait Bus {
+  fn read_u8(&mut self, addr: u16) -> u8;
+
+  fn write_u8(&mut self, addr: u16, value: u8);
+
+  /// Asked when the CPU executes STOP: whether a CGB speed switch is armed. The
+  /// CPU then flips its clock frequency and, only if the clock accepts that,
+  /// calls `switch_speed`.
+  fn speed_switch_armed(&self) -> bool {
+    false
+  }
+
+  /// Completes the speed switch `speed_switch_armed` reported.
+  fn switch_speed(&mut self) {}
+
+  /// Returns and clears the first watchpoint hit since the last call.
+  fn take_watch_hit(&mut self) -> Option<WatchHit> {
+    None
+  }
+
+  /// Advances the peripherals by `cycles` T-cycles. With
+  /// `ExecMode::InstructionAtomic` it's called once after each instruction with
+  /// all the T-cycles it took; with `ExecMode::TCycleStepped`, once per M-cycle
+  /// with 4. A stopped CPU ticks 4 per step in either mode.
+  fn tick(&mut self, _cycles: usize) {}
+}
+
+impl<B: Bus + ?Sized> Bus for &mut B {
+  fn read_u8(&mut self, addr: u16) -> u8 {
+    (**self).read_u8(addr)
+  }
+
+  fn write_u8(&mut self, addr: u16, value: u8) {
+    (**self).write_u8(addr, value)
+  }
+
+  fn speed_switch_armed(&self) -> bool {
+    (**self).speed_switch_armed()
+  }
+
+  fn switch_speed(&mut self) {
+    (**self).switch_speed()
+  }
+
+  fn take_watch_hit(&mut self) -> Option<WatchHit> {
+    (**self).take_watch_hit()
+  }
+
+  fn tick(&mut self, cycles: usize) {
+    (**self).tick(cycles)
+  }
+}
+
+impl<B: Bus + ?Sized> Bus for Box<B> {
+  fn read_u8(&mut self, addr: u16) -> u8 {
+    (**self).read_u8(addr)
+  }
+
+  fn write_u8(&mut self, addr: u16, value: u8) {
+    (**self).write_u8(addr, value)
+  }
+
+  fn speed_switch_armed(&self) -> bool {
+    (**self).speed_switch_armed()
+  }
+
+  fn switch_speed(&mut self) {
+    (**self).switch_speed()
+  }
+
+  fn take_watch_hit(&mut self) -> Option<WatchHit> {
+    (**self).take_watch_hit()
+  }
+
+  fn tick(&mut self, cycles: usize) {
+    (**self).tick(cycles)
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use alloc::vec::Vec;
+
+  use hw::cpu::Processor;
+  use hw::cpu::clock::{Clock, Frequency};
+  use hw::cpu::register::Register;
+  use super::*;
+
+  /// 64KB of plain RAM, for running instructions without a cartridge.
+  pub struct FlatBus {
+    pub mem: Vec<u8>,
+    /// Every `tick`, in order.
+    pub ticks: Vec<usize>,
+  }
+
+  impl FlatBus {
+    /// All zeroes apart from `program`, starting at `at`.
+    pub fn with_program(at: u16, program: &[u8]) -> FlatBus {
+      let mut mem = vec![0; 0x10000];
+      mem[at as usize .. at as usize + program.len()].copy_from_slice(program);
+      FlatBus { mem, ticks: Vec::new() }
+    }
+  }
+
+  impl Bus for FlatBus {
+    fn read_u8(&mut self, addr: u16) -> u8 {
+      self.mem[addr as usize]
+    }
+
+    fn write_u8(&mut self, addr: u16, value: u8) {
+      self.mem[addr as usize] = value;
+    }
+
+    fn tick(&mut self, cycles: usize) {
+      self.ticks.push(cycles);
+    }
+  }
+
+  /// Runs `LD A,0x42` at 0x0100 on `bus`, which only has to be `Sized`.
+  fn load_a<B: Bus>(mut bus: B) -> u8 {
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    cpu.step(&mut bus, &mut Clock::new(Frequency::Single)).unwrap();
+    cpu.regs().af().upper().get()
+  }
+
+  #[test]
+  fn executor_runs_on_trait_objects() {
+    let program = [0x3E, 0x42];
+
+    let boxed: Box<dyn Bus> = Box::new(FlatBus::with_program(0x0100, &program));
+    assert_eq!(load_a(boxed), 0x42);
+
+    let mut flat = FlatBus::with_program(0x0100, &program);
+    let borrowed: &mut dyn Bus = &mut flat;
+    assert_eq!(load_a(borrowed), 0x42);
+
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    let object: &mut dyn Bus = &mut flat;
+    assert_eq!(cpu.step(object, &mut Clock::new(Frequency::Single)).unwrap(), 8);
+  }
+}
@@ -15,19 +15,39 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-use std::convert::{Into, TryFrom, TryInto};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp;
+use core::convert::{Into, TryFrom, TryInto};
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::RangeInclusive;
+use core::result;
+use core::str;
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
 use std::fs;
+#[cfg(feature = "std")]
 use std::io;
+#[cfg(feature = "std")]
 use std::io::Read;
-use std::marker::PhantomData;
-use std::mem;
+#[cfg(feature = "std")]
 use std::path::Path;
-use std::result;
-use std::str;
+
+use super::cpu::disasm::Disassembler;
+use super::cpu::instr::Instr;
+use super::cpu::interrupt::Interrupt;
+use super::mbc::{self, MBCRegister};
+use super::model::Model;
 
 use self::regions::Region;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum Component {
   ROM(ROMNum),
   MBC(MBCNum),
@@ -36,6 +56,27 @@ pub enum Component {
   RAM(RAMNum),
   SRAM,
   Timer,
+  /// MBC3's real-time clock, whose state belongs in the save file alongside
+  /// RAM. Always accompanies `Timer`.
+  RTC,
+  Rumble,
+  PocketCam,
+  BandaiTAMA5,
+  HudsonHUC1,
+  HudsonHUC3,
+}
+
+/// A `Component` without its size or model details.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum ComponentKind {
+  ROM,
+  MBC,
+  Battery,
+  MMM,
+  RAM,
+  SRAM,
+  Timer,
+  RTC,
   Rumble,
   PocketCam,
   BandaiTAMA5,
@@ -49,7 +90,114 @@ pub struct Cartridge {
   is_cgb: bool,
   is_sgb: bool,
   rom: ROM,
+  rom_size: ROMNum,
+  ram_size: RAMNum,
   components: Vec<Component>,
+  /// Global checksum of the current contents, kept in step with every write.
+  global_sum: u16,
+  /// See `CartOptions::infer_ram`.
+  infer_ram: bool,
+  /// Set by `new_recording_checksum`.
+  checksum_status: Option<ChecksumStatus>,
+}
+
+/// A snapshot of the decoded header, for tools that want to present a
+/// cartridge without reaching into the parser.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct CartridgeInfo {
+  pub title: String,
+  pub is_cgb: bool,
+  pub is_sgb: bool,
+  pub rom_size: ROMNum,
+  pub ram_size: RAMNum,
+  pub rom_banks: usize,
+  pub ram_banks: usize,
+  pub components: Vec<Component>,
+}
+
+/// Whether the cartridge type and RAM size bytes agree about external RAM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RamConsistency {
+  Consistent,
+  TypeSaysRamButSizeZero,
+  SizeSaysRamButTypeNoRam,
+}
+
+/// Something about the header that a licensed cartridge wouldn't have, often
+/// a sign of a bootleg or a rebuilt header. See `Cartridge::header_anomalies`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anomaly {
+  /// The logo doesn't match, so the cartridge wouldn't boot.
+  BadLogo,
+  /// The header checksum is wrong, so the cartridge wouldn't boot.
+  BadHeaderChecksum,
+  /// The global checksum was left as zero rather than filled in.
+  ZeroGlobalChecksum,
+  /// The global checksum is filled in but doesn't match the contents.
+  BadGlobalChecksum,
+  /// Licensee code 0x00 (none) on a cartridge with a title.
+  NoLicensee,
+  /// The file is a different size than the header declares.
+  RomSizeMismatch,
+  /// The type and RAM size bytes disagree.
+  RamMismatch(RamConsistency),
+}
+
+/// A problem `Cartridge::load` worked around rather than failing on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Warning {
+  /// The type byte is unknown; the cartridge is treated as ROM only.
+  UnknownComponents(u8),
+  /// The ROM size byte is unknown; 32KB is assumed, though the whole file is
+  /// still mapped.
+  UnknownROMSize(u8),
+  /// The RAM size byte is unknown; no RAM is assumed beyond what the type implies.
+  UnknownRAMSize(u8),
+  /// Computed and stored header checksum differ.
+  BadHeaderChecksum(u8, u8),
+  /// Computed and stored global checksum differ.
+  BadGlobalChecksum(u16, u16),
+  BadLogo,
+  /// Declared and actual ROM size in bytes differ.
+  SizeMismatch(usize, usize),
+}
+
+/// How the checksums stored in the header compared with the contents, as
+/// recorded by `Cartridge::new_recording_checksum`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChecksumStatus {
+  /// Computed and stored header checksum.
+  pub header: (u8, u8),
+  /// Computed and stored global checksum.
+  pub global: (u16, u16),
+}
+
+impl ChecksumStatus {
+  /// Whether the header checksum matches. The boot ROM refuses a cartridge
+  /// when it doesn't.
+  pub fn header_ok(&self) -> bool {
+    self.header.0 == self.header.1
+  }
+
+  /// Whether the global checksum matches. Hardware never checks it.
+  pub fn global_ok(&self) -> bool {
+    self.global.0 == self.global.1
+  }
+}
+
+/// Overrides applied when the cartridge is parsed or mapped, for dumps whose
+/// header is wrong.
+#[derive(Clone, Debug, Default)]
+pub struct CartOptions {
+  mbc: Option<MBCNum>,
+  infer_ram: bool,
+}
+
+/// Accumulates the global checksum over a ROM fed in pieces, in any order.
+#[derive(Clone, Debug, Default)]
+pub struct GlobalChecksum {
+  sum: u16,
 }
 
 #[derive(Debug)]
@@ -65,6 +213,7 @@ struct ROMSlice<'a, T: PartialEq + 'static> {
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum ROMNum {
   N2,
   N4,
@@ -79,6 +228,7 @@ pub enum ROMNum {
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum RAMNum {
   N0,
   N1_2kB,
@@ -88,6 +238,7 @@ pub enum RAMNum {
 }
 
 #[derive (Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
 pub enum MBCNum {
   N1,
   N2,
@@ -102,26 +253,79 @@ pub enum CartErr {
   UnknownComponents(u8),
   UnknownROMSize(usize),
   UnknownRAMSize(usize),
+  #[cfg(feature = "std")]
   IOError(io::Error),
+  #[cfg(feature = "zip")]
+  ZipError(zip::result::ZipError),
+  /// The archive holds no `.gb` or `.gbc` file.
+  #[cfg(feature = "zip")]
+  NoROMInArchive,
+  /// The archive holds this many ROMs, and there's no telling which is wanted.
+  #[cfg(feature = "zip")]
+  ManyROMsInArchive(usize),
   BadHeaderChecksum(u8, u8),
   RegionOOB,
+  OffsetOOB(usize),
+  BadPatch(usize),
+  SizeMismatch(usize, usize),
+  /// This component duplicates another or lacks one it depends on.
+  InconsistentComponents(Component),
+  /// The ROM holds only this many bytes, too few to parse at all.
+  TooSmall(usize),
 }
 
 
 const KILOBYTE_BYTES: usize = 1024;
+const ROM_BANK_BYTES: usize = 16 * KILOBYTE_BYTES;
+/// The bitmap the boot ROM compares against the header before it will run a cartridge.
+const NINTENDO_LOGO: [u8; 0x30] = [
+  0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83,
+  0x00, 0x0C, 0x00, 0x0D, 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E,
+  0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99, 0xBB, 0xBB, 0x67, 0x63,
+  0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+/// Old licensee code the SGB BIOS requires before it honours any SGB commands.
+const SGB_LICENSEE: u8 = 0x33;
+/// CGB flag values.
+const CGB_ENHANCED: u8 = 0x80;
+const CGB_ONLY: u8 = 0xC0;
+/// Header byte of a one-packet PCT_TRN (command 0x14), which uploads a border.
+const SGB_PCT_TRN: u8 = 0x14 << 3 | 1;
+const SGB_PACKET_BYTES: usize = 16;
+/// Value of unprogrammed ROM, used when padding.
+const UNUSED_ROM_FILL: u8 = 0xFF;
+const RAM_BANK_BYTES: usize = 8 * KILOBYTE_BYTES;
+/// The MMM01 menu occupies the last two ROM banks.
+const MMM01_MENU_BYTES: usize = 2 * ROM_BANK_BYTES;
+/// MBC2 carries 512 half-bytes of RAM on the controller itself.
+pub const MBC2_RAM_BYTES: usize = 512;
+/// RAM assumed for an SRAM component when the header declares none, as types
+/// 0x0C and 0x0D often do. One bank covers everything MMM01 can map.
+pub const SRAM_DEFAULT_BYTES: usize = RAM_BANK_BYTES;
 
 // TODO is there a better way?
 pub mod regions {
-  use std::marker::PhantomData;
+  use core::marker::PhantomData;
 
   /// Specifies a memory region within the cartridge address space.
   /// Lower bound is inclusive; upper bound is exclusive.
   #[derive(Debug)]
-  pub struct Region<'a, T: 'a>(pub usize, pub usize, PhantomData<&'a T>);
+  pub struct Region<'a, T: 'a + ?Sized>(pub usize, pub usize, PhantomData<&'a T>);
+
+  impl<'a, T: ?Sized> Region<'a, T> {
+    /// A region for a header field the crate doesn't define itself.
+    pub const fn new(start: usize, end: usize) -> Region<'a, T> {
+      Region(start, end, PhantomData)
+    }
+  }
 
   pub const META_ENTRY: Region<[u8; 0x4]>  = Region(0x100, 0x104, PhantomData);
   pub const META_LOGO: Region<[u8; 0x30]>   = Region(0x104, 0x134, PhantomData);
   pub const META_TITLE: Region<[u8; 0x10]>  = Region(0x134, 0x144, PhantomData);
+  /// The title on CGB cartridges, cut short by the manufacturer code and CGB flag.
+  pub const META_TITLE_CGB: Region<[u8; 0xB]> = Region(0x134, 0x13F, PhantomData);
   pub const META_MANUFACTURER: Region<u32>  = Region(0x13F, 0x143, PhantomData);
   pub const META_CGB_FLAG: Region<u8>      = Region(0x143, 0x144, PhantomData);
   pub const META_LICENSEE: Region<u16>      = Region(0x144, 0x146, PhantomData);
@@ -140,11 +344,11 @@ pub mod regions {
   pub const EXEC_BOOT: Region<[u8; 256]>   = Region(0x0, 0x256, PhantomData);
 }
 
-impl<'a, T> Region<'a, T> where T: PartialEq {
+impl<'a, T: ?Sized> Region<'a, T> where T: PartialEq {
 
   fn is_in_bounds(&self, rom: &'a ROM) -> bool {
     !(self.0 >= rom.size_bytes() || self.1 < self.0
-      || self.1 >= rom.size_bytes())
+      || self.1 > rom.size_bytes())
   }
 
 }
@@ -159,46 +363,263 @@ impl<'a> Cartridge {
     Ok(x)
   }
 
-  pub fn new_no_check(bytes: Vec<u8>) -> Result<Cartridge> {
+  /// Like `new`, with the parsing overrides in `options` applied.
+  pub fn with_options(bytes: Vec<u8>, options: &CartOptions) -> Result<Cartridge> {
+    let mut sum = GlobalChecksum::new();
+    sum.update(&bytes, 0);
     let rom = try!(ROM::from_raw_bytes(bytes));
 
-    let title = try!(read_title(&rom));
-    let components = try!(decode_components(&rom));
-    let is_cgb = try!(decode_is_cgb(&rom));
-    let is_sgb = try!(decode_is_sgb(&rom));
+    let mut x = Cartridge::unparsed(rom, sum.finalize());
+    x.infer_ram = options.infers_ram();
+    try!(x.refresh_metadata());
+    try!(check_header_sum(&x.rom));
+    Ok(x)
+  }
+
+  /// Parses each ROM with `new`, keeping the results in input order. With the
+  /// `rayon` feature the ROMs are parsed in parallel.
+  pub fn parse_many<I: IntoIterator<Item = Vec<u8>>>(roms: I) -> Vec<Result<Cartridge>> {
+    #[cfg(feature = "rayon")]
+    let results = roms.into_iter().collect::<Vec<_>>().into_par_iter().map(Cartridge::new).collect();
+    #[cfg(not(feature = "rayon"))]
+    let results = roms.into_iter().map(Cartridge::new).collect();
+    results
+  }
 
-    let rom = Cartridge {
-      title: title,
-      is_cgb,
-      is_sgb,
-      rom: rom,
-      components: components,
+  pub fn new_no_check(bytes: Vec<u8>) -> Result<Cartridge> {
+    let mut sum = GlobalChecksum::new();
+    sum.update(&bytes, 0);
+    Cartridge::with_global_sum(bytes, sum.finalize())
+  }
+
+  /// Like `new_no_check`, but compares both checksums while loading so that
+  /// `checksum_status` can report a mismatch instead of failing.
+  pub fn new_recording_checksum(bytes: Vec<u8>) -> Result<Cartridge> {
+    let mut x = try!(Cartridge::new_no_check(bytes));
+    let header = try!(header_sums(&x.rom));
+    let stored = try!(x.rom.read_u16_be(regions::META_CHECKSUM_ALL.0));
+    x.checksum_status = Some(ChecksumStatus {
+      header,
+      global: (x.global_sum, stored),
+    });
+    Ok(x)
+  }
+
+  /// Parses whatever can be parsed, collecting every problem it works around
+  /// instead of failing. Only a ROM too short to hold a header is rejected.
+  /// Checksums are recorded as by `new_recording_checksum`.
+  pub fn load(bytes: Vec<u8>) -> Result<(Cartridge, Vec<Warning>)> {
+    let mut sum = GlobalChecksum::new();
+    sum.update(&bytes, 0);
+    let rom = try!(ROM::from_raw_bytes(bytes));
+    let mut x = Cartridge::unparsed(rom, sum.finalize());
+    let mut warnings = Vec::new();
+
+    x.is_cgb = try!(decode_is_cgb(&x.rom));
+    x.title = try!(read_title(&x.rom, x.is_cgb));
+    x.is_sgb = try!(decode_is_sgb(&x.rom));
+    match decode_rom_size(&x.rom) {
+      Ok(n) => {
+        let (declared, actual) = (n.clone().size_bytes(), x.rom.size_bytes());
+        if declared != actual {
+          warnings.push(Warning::SizeMismatch(declared, actual));
+        }
+        x.rom_size = n;
+      },
+      Err(CartErr::UnknownROMSize(code)) => warnings.push(Warning::UnknownROMSize(code as u8)),
+      Err(e) => return Err(e),
+    }
+    match decode_ram_size(&x.rom) {
+      Ok(n) => x.ram_size = n,
+      Err(CartErr::UnknownRAMSize(code)) => warnings.push(Warning::UnknownRAMSize(code as u8)),
+      Err(e) => return Err(e),
+    }
+    x.components = match decode_components(&x.rom, x.rom_size.clone(), x.ram_size.clone(), false) {
+      Ok(comps) => comps,
+      Err(CartErr::UnknownComponents(code)) => {
+        warnings.push(Warning::UnknownComponents(code));
+        vec![Component::ROM(x.rom_size.clone())]
+      },
+      Err(e) => return Err(e),
     };
 
+    let logo = &regions::META_LOGO;
+    if x.rom.bytes.get(logo.0 .. logo.1) != Some(&NINTENDO_LOGO[..]) {
+      warnings.push(Warning::BadLogo);
+    }
+    let header = try!(header_sums(&x.rom));
+    if header.0 != header.1 {
+      warnings.push(Warning::BadHeaderChecksum(header.0, header.1));
+    }
+    let global = (x.global_sum, try!(x.rom.read_u16_be(regions::META_CHECKSUM_ALL.0)));
+    if global.0 != global.1 {
+      warnings.push(Warning::BadGlobalChecksum(global.0, global.1));
+    }
+    x.checksum_status = Some(ChecksumStatus { header, global });
+
+    Ok((x, warnings))
+  }
+
+  fn with_global_sum(bytes: Vec<u8>, global_sum: u16) -> Result<Cartridge> {
+    let rom = try!(ROM::from_raw_bytes(bytes));
+
+    let mut rom = Cartridge::unparsed(rom, global_sum);
+    try!(rom.refresh_metadata());
+
     Ok(rom)
   }
 
+  /// Assembles a cartridge from already-decoded metadata, leaving the header
+  /// bytes in `rom_bytes` alone. Sizes come from the `ROM` and `RAM`
+  /// components, defaulting to 32KB and none.
+  pub fn from_parts(title: &str, components: Vec<Component>, is_cgb: bool, is_sgb: bool,
+                    rom_bytes: Vec<u8>) -> Result<Cartridge> {
+    try!(check_components(&components));
+
+    let mut sum = GlobalChecksum::new();
+    sum.update(&rom_bytes, 0);
+    let rom = try!(ROM::from_raw_bytes(rom_bytes));
+
+    let mut x = Cartridge::unparsed(rom, sum.finalize());
+    for c in components.iter() {
+      match *c {
+        Component::ROM(ref n) => x.rom_size = n.clone(),
+        Component::RAM(ref n) => x.ram_size = n.clone(),
+        _ => {},
+      }
+    }
+    x.title = String::from(title);
+    x.is_cgb = is_cgb;
+    x.is_sgb = is_sgb;
+    x.components = components;
+    Ok(x)
+  }
+
+  /// Placeholder metadata, to be filled in by `refresh_metadata`.
+  fn unparsed(rom: ROM, global_sum: u16) -> Cartridge {
+    Cartridge {
+      title: String::new(),
+      is_cgb: false,
+      is_sgb: false,
+      rom: rom,
+      rom_size: ROMNum::N2,
+      ram_size: RAMNum::N0,
+      components: Vec::new(),
+      global_sum,
+      infer_ram: false,
+      checksum_status: None,
+    }
+  }
+
   // TODO condense into one Result<_, _>
+  #[cfg(feature = "std")]
   pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge> {
-    let rom: Vec<u8> = {
-      let mut file = match fs::File::open(path) {
-        Ok(x) => x,
-        Err(x) => return Err(CartErr::IOError(x))
-      };
-      let mut bytes = Vec::<u8>::new();
-      match file.read_to_end(&mut bytes) {
-        Ok(x) => bytes,
-        Err(x) => return Err(CartErr::IOError(x)),
-      }
+    let file = match fs::File::open(path) {
+      Ok(x) => x,
+      Err(x) => return Err(CartErr::IOError(x))
+    };
+
+    Cartridge::from_reader(file)
+  }
+
+  /// Loads the only `.gb` or `.gbc` file in a zip archive.
+  #[cfg(feature = "zip")]
+  pub fn from_zip_file<P: AsRef<Path>>(path: P) -> Result<Cartridge> {
+    let file = match fs::File::open(path) {
+      Ok(x) => x,
+      Err(x) => return Err(CartErr::IOError(x))
     };
+    let mut archive = zip::ZipArchive::new(file).map_err(CartErr::ZipError)?;
+
+    let roms: Vec<String> = archive.file_names()
+      .filter(|name| is_rom_name(name))
+      .map(String::from)
+      .collect();
+    match roms.len() {
+      0 => Err(CartErr::NoROMInArchive),
+      1 => {
+        let entry = archive.by_name(&roms[0]).map_err(CartErr::ZipError)?;
+        Cartridge::from_reader(entry)
+      },
+      n => Err(CartErr::ManyROMsInArchive(n)),
+    }
+  }
+
+  /// Reads a ROM a bank at a time, summing it for the global checksum as it
+  /// arrives rather than in a second pass.
+  #[cfg(feature = "std")]
+  pub fn from_reader<R: Read>(mut reader: R) -> Result<Cartridge> {
+    let mut bytes = Vec::<u8>::new();
+    let mut sum = GlobalChecksum::new();
+    let mut chunk = vec![0u8; ROM_BANK_BYTES];
+    loop {
+      let n = match reader.read(&mut chunk) {
+        Ok(0) => break,
+        Ok(n) => n,
+        Err(ref x) if x.kind() == io::ErrorKind::Interrupted => continue,
+        Err(x) => return Err(CartErr::IOError(x)),
+      };
+      sum.update(&chunk[..n], bytes.len());
+      bytes.extend_from_slice(&chunk[..n]);
+    }
 
-    Cartridge::new(rom)
+    let x = try!(Cartridge::with_global_sum(bytes, sum.finalize()));
+    try!(check_header_sum(&x.rom));
+    Ok(x)
   }
 
   pub fn title(&'a self) -> &'a str {
     self.title.as_str()
   }
 
+  /// The title field exactly as stored, padding included: 16 bytes, or 11 on
+  /// CGB cartridges (see `title_region`). `title` is the decoded form with
+  /// trailing NULs removed.
+  pub fn title_bytes(&'a self) -> &'a [u8] {
+    let title = self.title_region();
+    self.rom.bytes.get(title.0 .. title.1).unwrap_or(&[])
+  }
+
+  /// Title, region and revision in the style archival sets name dumps by,
+  /// e.g. `POKEMON RED (Japan) (Rev A)`. Revision 0 is left out.
+  pub fn canonical_name(&self) -> String {
+    let title: String = self.title().chars()
+      .filter(|c| c.is_ascii_graphic() || *c == ' ')
+      .collect();
+    let title = match title.trim() {
+      "" => "Unknown",
+      t => t,
+    };
+    let region = match self.rom.bytes.get(regions::META_DEST.0) {
+      Some(&0x00) => "Japan",
+      _ => "World",
+    };
+    let mut name = format!("{} ({})", title, region);
+    match self.rom.bytes.get(regions::META_VERSION.0).cloned().unwrap_or(0) {
+      0 => {},
+      v @ 1 ..= 26 => name.push_str(&format!(" (Rev {})", (b'A' + v - 1) as char)),
+      v => name.push_str(&format!(" (Rev {})", v)),
+    }
+    name
+  }
+
+  /// Where the title lives on this cartridge: all 16 bytes on DMG cartridges,
+  /// the first 11 on CGB ones.
+  pub fn title_region(&self) -> Region<'static, [u8]> {
+    title_region(self.is_cgb)
+  }
+
+  /// The two-character new licensee code at 0x144, read little-endian.
+  pub fn licensee_code(&self) -> Option<u16> {
+    self.rom.region(&regions::META_LICENSEE).ok().map(|r| r.into())
+  }
+
+  /// The four-character manufacturer code at 0x13F, read little-endian. Only
+  /// later CGB cartridges fill it in; older ones have title bytes here.
+  pub fn manufacturer_code(&self) -> Option<u32> {
+    self.rom.region(&regions::META_MANUFACTURER).ok().map(|r| r.into())
+  }
+
   pub fn components(&'a self) -> &'a Vec<Component> {
     &self.components
   }
@@ -207,6 +628,32 @@ impl<'a> Cartridge {
     self.components.contains(&cmp)
   }
 
+  pub fn component_kinds(&'a self) -> impl Iterator<Item = ComponentKind> + 'a {
+    self.components.iter().map(Component::kind)
+  }
+
+  pub fn has_component_kind(&self, kind: ComponentKind) -> bool {
+    self.component_kinds().any(|k| k == kind)
+  }
+
+  /// A hash of the cartridge's hardware configuration, equal for any two carts
+  /// built from the same kinds of component. Sizes are left out, so every
+  /// MBC5+RAM+BATTERY cart hashes alike, but the MBC model is kept.
+  pub fn components_hash(&self) -> u64 {
+    let mut ids: Vec<u16> = self.components.iter()
+      .map(|c| match c.kind() {
+        ComponentKind::ROM | ComponentKind::RAM => c.id() & 0xFF00,
+        _ => c.id(),
+      })
+      .collect();
+    ids.sort();
+    // FNV-1a, which unlike the std hashers is stable and available without std.
+    ids.iter().fold(FNV_OFFSET, |hash, id| {
+      let hash = (hash ^ (id >> 8) as u64).wrapping_mul(FNV_PRIME);
+      (hash ^ (id & 0xFF) as u64).wrapping_mul(FNV_PRIME)
+    })
+  }
+
   pub fn is_cgb(&self) -> bool {
     self.is_cgb
   }
@@ -215,11 +662,464 @@ impl<'a> Cartridge {
     self.is_sgb
   }
 
+  /// The models the cartridge was made for, oldest first, so the last is the
+  /// best default. A CGB-only cart lists just the CGB; others list the DMG
+  /// and MGB, plus the SGB if they use its functions and the CGB if they're
+  /// enhanced for it. Any model with a DMG mode will still run them.
+  pub fn supported_models(&self) -> Vec<Model> {
+    let cgb_flag = self.rom.region(&regions::META_CGB_FLAG).ok().map(|r| r.into());
+    if self.is_cgb && cgb_flag == Some(CGB_ONLY) {
+      return vec![Model::CGB];
+    }
+    let mut models = vec![Model::DMG, Model::MGB];
+    if self.is_sgb {
+      models.push(Model::SGB);
+    }
+    if self.is_cgb {
+      models.push(Model::CGB);
+    }
+    models
+  }
+
+  /// Number of 16KB ROM banks declared by the header.
+  pub fn rom_bank_count(&self) -> usize {
+    self.rom_size.clone().size_bytes() / ROM_BANK_BYTES
+  }
 
+  /// Each 16KB bank the MBC can map, in order, covering `effective_rom_size`
+  /// including the 72, 80 and 96 bank sizes. Banks past the end of the file
+  /// are short or empty.
+  pub fn rom_banks(&'a self) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let count = self.effective_rom_size().div_ceil(ROM_BANK_BYTES);
+    (0 .. count).map(move |bank| self.rom_bank(bank))
+  }
+
+  /// Mask of the bank bits the declared ROM size has pins for, e.g. 0x0F for 16
+  /// banks. For the 72, 80 and 96 bank sizes it covers the next power of two,
+  /// so some masked selections are still past the end; those need reducing
+  /// modulo `rom_bank_count` instead, which also works for the other sizes.
+  pub fn rom_bank_mask(&self) -> usize {
+    self.rom_bank_count().next_power_of_two() - 1
+  }
+
+  /// Bytes of ROM the cartridge can present: the declared size or the file
+  /// length, whichever is larger. Offsets past the file but within this size
+  /// read as open bus.
+  pub fn effective_rom_size(&self) -> usize {
+    cmp::max(self.rom_size.clone().size_bytes(), self.rom.size_bytes())
+  }
+
+  /// Number of 8KB external RAM banks declared by the header. The 2KB size
+  /// counts as a single, partially populated bank.
+  pub fn ram_bank_count(&self) -> usize {
+    self.ram_size.clone().size_bytes().div_ceil(RAM_BANK_BYTES)
+  }
+
+  /// Whether the DMG boot ROM would hand control to this cartridge. Only the
+  /// logo and header checksum are verified; the global checksum is ignored.
+  pub fn boots_on_hardware(&self) -> bool {
+    Cartridge::quick_check(&self.rom.bytes)
+  }
+
+  /// Checks only what `boots_on_hardware` does, straight from raw bytes without
+  /// building a `Cartridge`. Meant for filtering large numbers of files.
+  pub fn quick_check(bytes: &[u8]) -> bool {
+    let logo = &regions::META_LOGO;
+    let range = &regions::RANGE_CHECKSUM;
+    let stored = regions::META_CHECKSUM_HDR.0;
+    if bytes.len() <= stored {
+      return false;
+    }
+
+    let sum = header_sum(&bytes[range.0 .. range.1]);
+    bytes[logo.0 .. logo.1] == NINTENDO_LOGO[..] && sum == bytes[stored]
+  }
+
+  /// Every suspicious header condition found, in the order `Anomaly` lists
+  /// them. Empty for a clean dump of a licensed cartridge.
+  pub fn header_anomalies(&self) -> Vec<Anomaly> {
+    let bytes = &self.rom.bytes;
+    let logo = &regions::META_LOGO;
+    let stored_global = self.rom.read_u16_be(regions::META_CHECKSUM_ALL.0).ok();
+    let licensee = bytes.get(regions::META_LICENSEE_OLD.0).cloned();
+
+    let mut found = Vec::new();
+    if bytes.get(logo.0 .. logo.1) != Some(&NINTENDO_LOGO[..]) {
+      found.push(Anomaly::BadLogo);
+    }
+    match header_sums(&self.rom) {
+      Ok((computed, stored)) if computed == stored => {},
+      _ => found.push(Anomaly::BadHeaderChecksum),
+    }
+    match stored_global {
+      Some(0) => found.push(Anomaly::ZeroGlobalChecksum),
+      _ if !self.global_checksum_ok() => found.push(Anomaly::BadGlobalChecksum),
+      _ => {},
+    }
+    if licensee == Some(0x00) && !self.title.is_empty() {
+      found.push(Anomaly::NoLicensee);
+    }
+    if self.rom.size_bytes() != self.rom_size.clone().size_bytes() {
+      found.push(Anomaly::RomSizeMismatch);
+    }
+    match self.ram_consistency() {
+      RamConsistency::Consistent => {},
+      c => found.push(Anomaly::RamMismatch(c)),
+    }
+    found
+  }
+
+  /// Whether this is an MMM01 multi-game cartridge. On these the last 32KB of
+  /// ROM is mapped at power-on and holds the menu, whose header declares the
+  /// MMM01; the header at 0x100 may instead belong to the first game.
+  pub fn is_mmm01(&self) -> bool {
+    if self.has_component_kind(ComponentKind::MMM) {
+      return true;
+    }
+    let len = self.rom.bytes.len();
+    if len < 2 * MMM01_MENU_BYTES || !Cartridge::quick_check(&self.rom.bytes[len - MMM01_MENU_BYTES ..]) {
+      return false;
+    }
+    matches!(self.rom.bytes[len - MMM01_MENU_BYTES + regions::META_COMPONENTS.0], 0x0B ..= 0x0D)
+  }
+
+  /// ROM offsets of the 16KB banks that start an MMM01 sub-game, i.e. hold a
+  /// bootable header at 0x100 past the offset. The menu in the last 32KB is
+  /// left out. Empty if the cartridge isn't MMM01.
+  pub fn mmm01_game_offsets(&self) -> Vec<usize> {
+    if !self.is_mmm01() {
+      return Vec::new();
+    }
+    let menu = self.rom.bytes.len().saturating_sub(MMM01_MENU_BYTES);
+    (0 .. menu).step_by(ROM_BANK_BYTES)
+      .filter(|&base| Cartridge::quick_check(&self.rom.bytes[base ..]))
+      .collect()
+  }
+
+  /// Guesses whether the game draws an SGB border: the header must enable SGB
+  /// functions and the ROM must hold a PCT_TRN packet, whose payload bytes are
+  /// always zero.
+  pub fn sgb_border_present(&self) -> bool {
+    let licensee = self.rom.bytes.get(regions::META_LICENSEE_OLD.0).cloned();
+    if !self.is_sgb || licensee != Some(SGB_LICENSEE) {
+      return false;
+    }
+
+    self.rom.bytes.windows(SGB_PACKET_BYTES).any(|packet| {
+      packet[0] == SGB_PCT_TRN && packet[1..].iter().all(|&b| b == 0)
+    })
+  }
+
+  pub fn describe(&self) -> CartridgeInfo {
+    CartridgeInfo {
+      title: self.title.clone(),
+      is_cgb: self.is_cgb,
+      is_sgb: self.is_sgb,
+      rom_size: self.rom_size.clone(),
+      ram_size: self.ram_size.clone(),
+      rom_banks: self.rom_bank_count(),
+      ram_banks: self.ram_bank_count(),
+      components: self.components.clone(),
+    }
+  }
+
+  /// The SHA-256 of the header from the title through the global checksum, for
+  /// matching a cartridge revision against a catalog.
+  pub fn header_digest(&self) -> [u8; 32] {
+    let start = regions::META_TITLE.0;
+    let end = regions::META_CHECKSUM_ALL.1;
+    sha256::digest(self.rom.bytes.get(start .. end).unwrap_or(&[]))
+  }
+
+  /// Cross-checks the type byte against the RAM size byte. MBC2's built-in RAM
+  /// is not declared by the size byte, so a size of 0 is consistent there.
+  pub fn ram_consistency(&self) -> RamConsistency {
+    let type_has_ram = self.has_component_kind(ComponentKind::RAM)
+      || self.has_component_kind(ComponentKind::SRAM);
+    let size_has_ram = self.ram_size != RAMNum::N0;
+
+    match (type_has_ram, size_has_ram) {
+      (true, false) => RamConsistency::TypeSaysRamButSizeZero,
+      (false, true) => RamConsistency::SizeSaysRamButTypeNoRam,
+      _ => RamConsistency::Consistent,
+    }
+  }
+
+  /// The memory bank controller, if the cartridge has one.
+  pub fn mbc(&self) -> Option<MBCNum> {
+    self.components.iter().filter_map(|c| match *c {
+      Component::MBC(ref n) => Some(n.clone()),
+      _ => None,
+    }).next()
+  }
+
+  /// Interrupts tied to the cartridge's components, for front-ends deciding
+  /// what to wire up. The cartridge has no interrupt line of its own, so this
+  /// only names the console sources a component's handling depends on; today
+  /// that's just the timer for an MBC3 clock.
+  pub fn possible_interrupt_sources(&self) -> Vec<Interrupt> {
+    let mut sources = Vec::new();
+    if self.has_component_kind(ComponentKind::Timer) {
+      sources.push(Interrupt::Timer);
+    }
+    sources
+  }
+
+  /// Address ranges whose writes control the cartridge's MBC registers. Empty
+  /// if it has no MBC.
+  pub fn mbc_register_ranges(&self) -> Vec<(RangeInclusive<u16>, MBCRegister)> {
+    self.mbc().map(|n| mbc::register_ranges(&n)).unwrap_or_default()
+  }
+
+  /// Bytes of external RAM the cartridge carries, including MBC2's built-in RAM
+  /// which the header does not declare, any RAM inferred by `CartOptions`, and
+  /// `SRAM_DEFAULT_BYTES` for an SRAM component the header gives no size.
+  pub fn save_ram_size_bytes(&self) -> usize {
+    if let Some(MBCNum::N2) = self.mbc() {
+      return MBC2_RAM_BYTES;
+    }
+    let declared = self.ram_size.clone().size_bytes();
+    self.components.iter().fold(declared, |size, c| match *c {
+      Component::RAM(ref n) => cmp::max(size, n.clone().size_bytes()),
+      Component::SRAM if size == 0 => SRAM_DEFAULT_BYTES,
+      _ => size,
+    })
+  }
+
+  /// Counts each mnemonic decoded by a linear sweep of ROM bank `bank`. Illegal
+  /// opcodes and a truncated final instruction are skipped a byte at a time.
+  #[cfg(feature = "std")]
+  pub fn instruction_histogram(&self, bank: usize) -> HashMap<&'static str, usize> {
+    let bytes = self.rom_bank(bank);
+    let mut counts = HashMap::new();
+    let mut at = 0;
+    while at < bytes.len() {
+      match Instr::decode(&bytes[at..]) {
+        Ok(instr) => {
+          if let Some(opcode) = instr.opcode() {
+            *counts.entry(opcode.mnemonic()).or_insert(0) += 1;
+          }
+          at += instr.len();
+        },
+        Err(_) => at += 1,
+      }
+    }
+    counts
+  }
+
+  /// Disassembles only the ROM bytes in `start..end`, as `(offset, text)` pairs,
+  /// so data around a known code block isn't misread as instructions. Each bank
+  /// is disassembled at the addresses the CPU sees it at when mapped in, and an
+  /// instruction cut off by the end of its bank is emitted as `DB` bytes.
+  pub fn disassemble_range(&self, start: usize, end: usize) -> Vec<(usize, String)> {
+    let end = cmp::min(end, self.rom.bytes.len());
+    let disassembler = Disassembler::new();
+    let mut out = Vec::new();
+    let mut chunk = start;
+    while chunk < end {
+      let chunk_end = cmp::min(end, (chunk / ROM_BANK_BYTES + 1) * ROM_BANK_BYTES);
+      let base = match chunk {
+        0 ..= 0x3FFF => chunk,
+        _ => 0x4000 + chunk % ROM_BANK_BYTES,
+      } as u16;
+      out.extend(disassembler
+        .disassemble(&self.rom.bytes[chunk .. chunk_end], base)
+        .into_iter()
+        .map(|(pc, text)| (chunk + pc.wrapping_sub(base) as usize, text)));
+      chunk = chunk_end;
+    }
+    out
+  }
+
+  /// The raw bytes of any header region, including ones this crate doesn't
+  /// decode. `T` only documents the field's type; no conversion is done.
+  pub fn read_region<T: PartialEq + ?Sized>(&self, region: &Region<T>) -> Result<&[u8]> {
+    if region.is_in_bounds(&self.rom) {
+      Ok(&self.rom.bytes[region.0 .. region.1])
+    } else {
+      Err(CartErr::RegionOOB)
+    }
+  }
+
+  /// The bytes of 16KB ROM bank `bank`, short or empty past the end of the file.
+  fn rom_bank(&self, bank: usize) -> &[u8] {
+    let len = self.rom.bytes.len();
+    let start = cmp::min(bank.saturating_mul(ROM_BANK_BYTES), len);
+    let end = cmp::min(start + ROM_BANK_BYTES, len);
+    &self.rom.bytes[start .. end]
+  }
+
+  /// Reads the ROM byte at `addr`, a flat offset into the file that ignores banking.
+  pub fn read_rom(&self, addr: usize) -> Option<u8> {
+    self.rom.bytes.get(addr).cloned()
+  }
+
+  /// Gives up the cartridge for its ROM contents, without copying them. Any
+  /// patches or size normalization applied since loading are kept.
+  pub fn into_rom_bytes(self) -> Vec<u8> {
+    self.rom.bytes
+  }
+
+  /// Overwrites the ROM byte at `offset`, returning the value it replaced.
+  ///
+  /// Header-derived fields are left untouched until `refresh_metadata` is called.
+  pub fn patch_byte(&mut self, offset: usize, value: u8) -> Result<u8> {
+    let old = try!(self.rom.patch_byte(offset, value));
+    let field = &regions::META_CHECKSUM_ALL;
+    if offset < field.0 || offset >= field.1 {
+      self.global_sum = self.global_sum.wrapping_sub(old as u16).wrapping_add(value as u16);
+    }
+    Ok(old)
+  }
+
+  /// The checksums as found when loaded, if the cartridge came from
+  /// `new_recording_checksum`. Later patches aren't reflected.
+  pub fn checksum_status(&self) -> Option<ChecksumStatus> {
+    self.checksum_status
+  }
+
+  /// Whether the global checksum stored in the header matches the contents.
+  /// Hardware never checks it.
+  pub fn global_checksum_ok(&self) -> bool {
+    self.rom.read_u16_be(regions::META_CHECKSUM_ALL.0).ok() == Some(self.global_sum)
+  }
+
+  /// Re-decodes the header-derived fields from the current ROM contents.
+  ///
+  /// On error the previously decoded fields are kept.
+  pub fn refresh_metadata(&mut self) -> Result<()> {
+    let is_cgb = try!(decode_is_cgb(&self.rom));
+    let title = try!(read_title(&self.rom, is_cgb));
+    let rom_size = try!(decode_rom_size(&self.rom));
+    let ram_size = try!(decode_ram_size(&self.rom));
+    let components = try!(decode_components(&self.rom, rom_size.clone(), ram_size.clone(),
+                                            self.infer_ram));
+    let is_sgb = try!(decode_is_sgb(&self.rom));
+
+    self.title = title;
+    self.rom_size = rom_size;
+    self.ram_size = ram_size;
+    self.components = components;
+    self.is_cgb = is_cgb;
+    self.is_sgb = is_sgb;
+
+    Ok(())
+  }
+
+  /// Applies an IPS patch to the ROM, then recomputes both checksums and the
+  /// header-derived fields.
+  ///
+  /// The patched ROM is built and re-parsed on a copy, so on error the
+  /// cartridge is left exactly as it was.
+  pub fn apply_ips(&mut self, patch: &[u8]) -> Result<()> {
+    let patch = try!(ips::Patch::parse(patch));
+    let mut bytes = self.rom.bytes.clone();
+    patch.apply(&mut bytes);
+
+    let mut rom = try!(ROM::from_raw_bytes(bytes));
+    let global_sum = try!(rom.fix_checksums());
+    let mut patched = Cartridge::unparsed(rom, global_sum);
+    patched.infer_ram = self.infer_ram;
+    patched.checksum_status = self.checksum_status;
+    try!(patched.refresh_metadata());
+
+    *self = patched;
+    Ok(())
+  }
+
+  /// Pads the ROM with 0xFF up to the next 16KB bank boundary, or to the size
+  /// its header declares if that is larger, then recomputes the checksums. Only
+  /// trailing fill (0xFF or 0x00) past the declared size is truncated; data
+  /// there is kept. Returns the original length.
+  pub fn normalize_size(&mut self) -> Result<usize> {
+    let declared = self.rom_size.clone().size_bytes();
+    let len = self.rom.bytes.len();
+    let within = cmp::min(declared, len);
+    let data_end = self.rom.bytes[within ..].iter()
+      .rposition(|&b| b != UNUSED_ROM_FILL && b != 0x00)
+      .map_or(within, |i| within + i + 1);
+    let target = cmp::max(declared, data_end.div_ceil(ROM_BANK_BYTES) * ROM_BANK_BYTES);
+    if len != target {
+      self.rom.bytes.resize(target, UNUSED_ROM_FILL);
+      self.global_sum = try!(self.rom.fix_checksums());
+    }
+    Ok(len)
+  }
+
+  /// Lists every offset at which the two ROMs differ, as `(offset, ours, theirs)`.
+  ///
+  /// Both ROMs must be the same length.
+  pub fn diff(&self, other: &Cartridge) -> Result<Vec<(usize, u8, u8)>> {
+    let (ours, theirs) = (&self.rom.bytes, &other.rom.bytes);
+    if ours.len() != theirs.len() {
+      return Err(CartErr::SizeMismatch(ours.len(), theirs.len()));
+    }
+
+    Ok(ours.iter()
+      .zip(theirs.iter())
+      .enumerate()
+      .filter(|&(_, (a, b))| a != b)
+      .map(|(i, (&a, &b))| (i, a, b))
+      .collect())
+  }
+
+}
+
+impl CartOptions {
+  pub fn new() -> CartOptions {
+    CartOptions::default()
+  }
+
+  /// Uses `mbc` in place of the mapper named by the header. `None` restores
+  /// the detected one.
+  pub fn force_mbc(mut self, mbc: Option<MBCNum>) -> CartOptions {
+    self.mbc = mbc;
+    self
+  }
+
+  pub fn forced_mbc(&self) -> Option<&MBCNum> {
+    self.mbc.as_ref()
+  }
+
+  /// When the type byte includes RAM but the size byte says none, assume 8KB
+  /// rather than trusting the header. Off by default.
+  pub fn infer_ram(mut self, infer: bool) -> CartOptions {
+    self.infer_ram = infer;
+    self
+  }
+
+  pub fn infers_ram(&self) -> bool {
+    self.infer_ram
+  }
+}
+
+impl GlobalChecksum {
+  pub fn new() -> GlobalChecksum {
+    GlobalChecksum::default()
+  }
+
+  /// Adds `bytes`, which start at `offset` in the ROM. The checksum field
+  /// itself is skipped.
+  pub fn update(&mut self, bytes: &[u8], offset: usize) {
+    let field = &regions::META_CHECKSUM_ALL;
+    for (i, &b) in bytes.iter().enumerate() {
+      let at = offset + i;
+      if at < field.0 || at >= field.1 {
+        self.sum = self.sum.wrapping_add(b as u16);
+      }
+    }
+  }
+
+  pub fn finalize(self) -> u16 {
+    self.sum
+  }
 }
 
 impl ROM {
   fn from_raw_bytes(bytes: Vec<u8>) -> Result<ROM> {
+    if bytes.is_empty() {
+      return Err(CartErr::TooSmall(0));
+    }
     Ok(ROM {
       bytes,
     })
@@ -232,6 +1132,45 @@ impl ROM {
   fn size_bytes(&self) -> usize {
     self.bytes.len()
   }
+
+  fn read_u16_le(&self, offset: usize) -> Result<u16> {
+    match self.bytes.get(offset .. offset + 2) {
+      Some(b) => Ok(b[0] as u16 | (b[1] as u16) << 8),
+      None => Err(CartErr::OffsetOOB(offset)),
+    }
+  }
+
+  fn read_u32_le(&self, offset: usize) -> Result<u32> {
+    match self.bytes.get(offset .. offset + 4) {
+      Some(b) => Ok(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24),
+      None => Err(CartErr::OffsetOOB(offset)),
+    }
+  }
+
+  /// The global checksum is the one header field stored big-endian.
+  fn read_u16_be(&self, offset: usize) -> Result<u16> {
+    self.read_u16_le(offset).map(u16::swap_bytes)
+  }
+
+  fn patch_byte(&mut self, offset: usize, value: u8) -> Result<u8> {
+    match self.bytes.get_mut(offset) {
+      Some(b) => Ok(mem::replace(b, value)),
+      None => Err(CartErr::OffsetOOB(offset)),
+    }
+  }
+
+  /// Rewrites the header and global checksum fields to match the current
+  /// contents, returning the new global checksum.
+  fn fix_checksums(&mut self) -> Result<u16> {
+    let hdr = header_sum(self.region(&regions::RANGE_CHECKSUM)?.bytes());
+    self.bytes[regions::META_CHECKSUM_HDR.0] = hdr;
+
+    let all = try!(compute_global_sum(self));
+    self.bytes[regions::META_CHECKSUM_ALL.0] = (all >> 8) as u8;
+    self.bytes[regions::META_CHECKSUM_ALL.0 + 1] = (all & 0xFF) as u8;
+
+    Ok(all)
+  }
 }
 
 impl<'a, T> ROMSlice<'a, T> where T: PartialEq + Clone {
@@ -247,18 +1186,95 @@ impl<'a, T> ROMSlice<'a, T> where T: PartialEq + Clone {
     Err(CartErr::RegionOOB)
   }
 
-  fn into(self) -> T {
-    self.convert_from()
+  fn bytes(&self) -> &'a [u8] {
+    self.bytes
   }
+}
 
-  fn convert_from(&self) -> T {
-    let converted: &T = unsafe { mem::transmute(&self.bytes[self.region.0]) };
+impl<'a> ROMSlice<'a, u8> {
+  fn into(self) -> u8 {
+    self.bytes[0]
+  }
+}
 
-    converted.clone()
+impl<'a> ROMSlice<'a, u16> {
+  /// Little-endian. The global checksum is big-endian and read with
+  /// `ROM::read_u16_be` instead.
+  fn into(self) -> u16 {
+    self.rom.read_u16_le(self.region.0).unwrap_or(0)
   }
+}
 
-  fn bytes(&self) -> &'a [u8] {
-    self.bytes
+impl<'a> ROMSlice<'a, u32> {
+  fn into(self) -> u32 {
+    self.rom.read_u32_le(self.region.0).unwrap_or(0)
+  }
+}
+
+impl Component {
+  pub fn kind(&self) -> ComponentKind {
+    match *self {
+      Component::ROM(_) => ComponentKind::ROM,
+      Component::MBC(_) => ComponentKind::MBC,
+      Component::Battery => ComponentKind::Battery,
+      Component::MMM => ComponentKind::MMM,
+      Component::RAM(_) => ComponentKind::RAM,
+      Component::SRAM => ComponentKind::SRAM,
+      Component::Timer => ComponentKind::Timer,
+      Component::RTC => ComponentKind::RTC,
+      Component::Rumble => ComponentKind::Rumble,
+      Component::PocketCam => ComponentKind::PocketCam,
+      Component::BandaiTAMA5 => ComponentKind::BandaiTAMA5,
+      Component::HudsonHUC1 => ComponentKind::HudsonHUC1,
+      Component::HudsonHUC3 => ComponentKind::HudsonHUC3,
+    }
+  }
+
+  /// A stable number for this component: the variant in the high byte and its
+  /// size or model, as coded in the header, in the low byte. Ids are never
+  /// reused, so they're safe to store.
+  pub fn id(&self) -> u16 {
+    let (variant, detail): (u16, u8) = match *self {
+      Component::ROM(ref n) => (0, Into::<usize>::into(n.clone()) as u8),
+      Component::MBC(ref n) => (1, n.clone().into()),
+      Component::Battery => (2, 0),
+      Component::MMM => (3, 0),
+      Component::RAM(ref n) => (4, Into::<usize>::into(n.clone()) as u8),
+      Component::SRAM => (5, 0),
+      Component::Timer => (6, 0),
+      Component::Rumble => (7, 0),
+      Component::PocketCam => (8, 0),
+      Component::BandaiTAMA5 => (9, 0),
+      Component::HudsonHUC1 => (10, 0),
+      Component::HudsonHUC3 => (11, 0),
+      Component::RTC => (12, 0),
+    };
+    variant << 8 | detail as u16
+  }
+
+  /// The component with the given `id`, if there is one.
+  pub fn from_id(id: u16) -> Option<Component> {
+    let detail = (id & 0xFF) as usize;
+    let c = match (id >> 8, detail) {
+      (0, _) => Component::ROM(ROMNum::try_from(detail).ok()?),
+      (1, 1) => Component::MBC(MBCNum::N1),
+      (1, 2) => Component::MBC(MBCNum::N2),
+      (1, 3) => Component::MBC(MBCNum::N3),
+      (1, 5) => Component::MBC(MBCNum::N5),
+      (2, 0) => Component::Battery,
+      (3, 0) => Component::MMM,
+      (4, _) => Component::RAM(RAMNum::try_from(detail).ok()?),
+      (5, 0) => Component::SRAM,
+      (6, 0) => Component::Timer,
+      (7, 0) => Component::Rumble,
+      (8, 0) => Component::PocketCam,
+      (9, 0) => Component::BandaiTAMA5,
+      (10, 0) => Component::HudsonHUC1,
+      (11, 0) => Component::HudsonHUC3,
+      (12, 0) => Component::RTC,
+      _ => return None,
+    };
+    Some(c)
   }
 }
 
@@ -275,8 +1291,19 @@ impl Into<u8> for MBCNum {
 
 impl ROMNum {
   pub fn size_bytes(self) -> usize {
-    const _16KB: usize = 16 * KILOBYTE_BYTES;
-    return (self as usize) * _16KB
+    let banks = match self {
+      ROMNum::N2 => 2,
+      ROMNum::N4 => 4,
+      ROMNum::N8 => 8,
+      ROMNum::N16 => 16,
+      ROMNum::N32 => 32,
+      ROMNum::N64 => 64,
+      ROMNum::N128 => 128,
+      ROMNum::N72 => 72,
+      ROMNum::N80 => 80,
+      ROMNum::N96 => 96
+    };
+    banks * ROM_BANK_BYTES
   }
 }
 
@@ -328,6 +1355,28 @@ impl RAMNum {
   }
 }
 
+/// Sizes read like `32 KiB`, or `1 MiB` from a whole mebibyte up.
+impl fmt::Display for ROMNum {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write_size(f, self.clone().size_bytes())
+  }
+}
+
+impl fmt::Display for RAMNum {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write_size(f, self.clone().size_bytes())
+  }
+}
+
+fn write_size(f: &mut fmt::Formatter, bytes: usize) -> fmt::Result {
+  let kib = bytes / KILOBYTE_BYTES;
+  if kib >= 1024 && kib.is_multiple_of(1024) {
+    write!(f, "{} MiB", kib / 1024)
+  } else {
+    write!(f, "{} KiB", kib)
+  }
+}
+
 impl Into<usize> for RAMNum {
   fn into(self) -> usize {
     match self {
@@ -357,15 +1406,36 @@ impl TryFrom<usize> for RAMNum {
 
 // TODO use more specific param than just byte vec
 // TODO ...is there any way to determine that we're not reading garbage? does it matter?
-fn read_title(rom: &ROM) -> Result<String> {
-  Ok(String::from_utf8_lossy(&rom.region(&regions::META_TITLE)?.into()).into_owned())
+/// Decodes the bytes of `title_region`, so a CGB title stops short of the
+/// manufacturer code and CGB flag. Fails with `RegionOOB` on a ROM too short to
+/// hold the title, before any decoding is attempted.
+fn read_title(rom: &ROM, is_cgb: bool) -> Result<String> {
+  let region = title_region(is_cgb);
+  if !region.is_in_bounds(rom) {
+    return Err(CartErr::RegionOOB);
+  }
+  let bytes = &rom.bytes[region.0 .. region.1];
+  let title = String::from_utf8_lossy(bytes).into_owned();
+  Ok(title.trim_end_matches('\0').into())
+}
+
+/// All 16 title bytes on DMG cartridges, the first 11 on CGB ones.
+fn title_region(is_cgb: bool) -> Region<'static, [u8]> {
+  let end = if is_cgb { regions::META_TITLE_CGB.1 } else { regions::META_TITLE.1 };
+  Region::new(regions::META_TITLE.0, end)
+}
+
+#[cfg(feature = "zip")]
+fn is_rom_name(name: &str) -> bool {
+  let name = name.to_ascii_lowercase();
+  name.ends_with(".gb") || name.ends_with(".gbc")
 }
 
-fn decode_components(rom: &ROM) -> Result<Vec<Component>> {
-  let _romnum = try!(decode_rom_size(rom));
-  let _ramnum = try!(decode_ram_size(rom));
+/// With `infer_ram`, a RAM component the size byte leaves empty is given 8KB.
+fn decode_components(rom: &ROM, _romnum: ROMNum, _ramnum: RAMNum,
+                     infer_ram: bool) -> Result<Vec<Component>> {
 
-  let comps = match rom.region(&regions::META_COMPONENTS)?.into() {
+  let mut comps = match rom.region(&regions::META_COMPONENTS)?.into() {
     0x0 => vec![Component::ROM(_romnum)],
     0x1 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N1)],
     0x2 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N1), Component::RAM(_ramnum)],
@@ -379,12 +1449,12 @@ fn decode_components(rom: &ROM) -> Result<Vec<Component>> {
     0xD => vec![Component::ROM(_romnum), Component::MMM, Component::SRAM,
                   Component::Battery],
     0xF => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N3), Component::Timer,
-                  Component::Battery],
+                  Component::RTC, Component::Battery],
     0x10 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N3), Component::Timer,
-                  Component::RAM(_ramnum), Component::Battery],
+                  Component::RTC, Component::RAM(_ramnum), Component::Battery],
     0x11 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N3)],
     0x12 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N3), Component::RAM(_ramnum)],
-    0x13 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N5), Component::RAM(_ramnum),
+    0x13 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N3), Component::RAM(_ramnum),
                   Component::Battery],
     0x19 => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N5)],
     0x1A => vec![Component::ROM(_romnum), Component::MBC(MBCNum::N5), Component::RAM(_ramnum)],
@@ -402,9 +1472,37 @@ fn decode_components(rom: &ROM) -> Result<Vec<Component>> {
     x => return Err(CartErr::UnknownComponents(x)),
   };
 
+  if infer_ram {
+    for c in comps.iter_mut().filter(|c| **c == Component::RAM(RAMNum::N0)) {
+      *c = Component::RAM(RAMNum::N1_8kB);
+    }
+  }
+
   Ok(comps)
 }
 
+/// Rejects duplicate kinds, an MBC alongside MMM01, and a battery, timer or
+/// rumble motor without the hardware it hangs off.
+fn check_components(comps: &[Component]) -> Result<()> {
+  let has = |kind| comps.iter().any(|c| c.kind() == kind);
+  for (i, c) in comps.iter().enumerate() {
+    let ok = match *c {
+      _ if comps[..i].iter().any(|d| d.kind() == c.kind()) => false,
+      Component::MBC(_) => !has(ComponentKind::MMM),
+      Component::Battery => has(ComponentKind::RAM) || has(ComponentKind::SRAM)
+        || has(ComponentKind::Timer) || comps.contains(&Component::MBC(MBCNum::N2)),
+      Component::Timer => comps.contains(&Component::MBC(MBCNum::N3)),
+      Component::RTC => has(ComponentKind::Timer),
+      Component::Rumble => comps.contains(&Component::MBC(MBCNum::N5)),
+      _ => true,
+    };
+    if !ok {
+      return Err(CartErr::InconsistentComponents(c.clone()));
+    }
+  }
+  Ok(())
+}
+
 fn decode_rom_size(rom: &ROM) -> Result<ROMNum> {
   (rom.region(&regions::META_ROM_SIZE)?.into() as usize).try_into()
 }
@@ -413,9 +1511,10 @@ fn decode_ram_size(rom: &ROM) -> Result<RAMNum> {
   (rom.region(&regions::META_RAM_SIZE)?.into() as usize).try_into()
 }
 
+/// True for both CGB-enhanced (0x80) and CGB-only (0xC0) cartridges.
 fn decode_is_cgb(rom: &ROM) -> Result<bool> {
   let flag: u8 = rom.region(&regions::META_CGB_FLAG)?.into();
-  Ok(flag == 0x80)
+  Ok(flag == CGB_ENHANCED || flag == CGB_ONLY)
 }
 
 fn decode_is_sgb(rom: &ROM) -> Result<bool> {
@@ -423,18 +1522,1092 @@ fn decode_is_sgb(rom: &ROM) -> Result<bool> {
   Ok(flag == 0x3)
 }
 
+/// Sums every ROM byte except the two global checksum bytes themselves.
+fn compute_global_sum(rom: &ROM) -> Result<u16> {
+  let field = &regions::META_CHECKSUM_ALL;
+  if !field.is_in_bounds(rom) {
+    return Err(CartErr::RegionOOB);
+  }
+
+  let mut sum = GlobalChecksum::new();
+  sum.update(&rom.bytes, 0);
+  Ok(sum.finalize())
+}
+
 fn check_header_sum(rom: &ROM) -> Result<()> {
-  let bytes = rom.region(&regions::RANGE_CHECKSUM)?.into();
+  let (computed, checksum) = try!(header_sums(rom));
+  if computed == checksum {
+    Ok(())
+  } else {
+    Err(CartErr::BadHeaderChecksum(computed, checksum))
+  }
+}
+
+/// The header checksum computed from `rom` and the one stored in it.
+fn header_sums(rom: &ROM) -> Result<(u8, u8)> {
+  let bytes = rom.region(&regions::RANGE_CHECKSUM)?.bytes();
   let checksum = rom.region(&regions::META_CHECKSUM_HDR)?.into();
+  Ok((header_sum(bytes), checksum))
+}
+
+/// The header checksum over `bytes`, the 0x134-0x14C range it covers. The boot
+/// ROM refuses to start a cartridge whose stored checksum doesn't match.
+fn header_sum(bytes: &[u8]) -> u8 {
+  bytes.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1))
+}
 
-  let mut sum: isize = 0;
-  for &b in bytes.into_iter() {
-    sum = sum - (b as isize) - 1;
+mod ips {
+  use alloc::vec::Vec;
+
+  use super::{CartErr, Result};
+
+  const HEADER: &[u8] = b"PATCH";
+  const FOOTER: &[u8] = b"EOF";
+
+  /// A parsed IPS patch. Offsets are 24-bit big-endian; a zero-length record is
+  /// an RLE run of a single byte.
+  pub struct Patch {
+    records: Vec<Record>,
+    truncate: Option<usize>,
   }
 
-  if (sum & 0xFF) as u8 == checksum {
-    Ok(())
-  } else {
-    Err(CartErr::BadHeaderChecksum(sum as u8, checksum))
+  enum Record {
+    Data(usize, Vec<u8>),
+    Run(usize, usize, u8),
+  }
+
+  struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+  }
+
+  impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+      if self.bytes.len() - self.pos < n {
+        return Err(CartErr::BadPatch(self.pos));
+      }
+      let taken = &self.bytes[self.pos .. self.pos + n];
+      self.pos += n;
+      Ok(taken)
+    }
+
+    fn be(&mut self, n: usize) -> Result<usize> {
+      Ok(try!(self.take(n)).iter().fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+
+    fn remaining(&self) -> usize {
+      self.bytes.len() - self.pos
+    }
+  }
+
+  impl Patch {
+    pub fn parse(patch: &[u8]) -> Result<Patch> {
+      let mut rd = Reader { bytes: patch, pos: 0 };
+      if try!(rd.take(HEADER.len())) != HEADER {
+        return Err(CartErr::BadPatch(0));
+      }
+
+      let mut records = Vec::new();
+      loop {
+        if rd.remaining() >= FOOTER.len() && &patch[rd.pos .. rd.pos + FOOTER.len()] == FOOTER {
+          rd.pos += FOOTER.len();
+          break;
+        }
+
+        let offset = try!(rd.be(3));
+        let len = try!(rd.be(2));
+        if len == 0 {
+          let run = try!(rd.be(2));
+          let value = try!(rd.take(1))[0];
+          records.push(Record::Run(offset, run, value));
+        } else {
+          records.push(Record::Data(offset, try!(rd.take(len)).to_vec()));
+        }
+      }
+
+      // Lunar IPS extension: an optional 24-bit size to truncate the output to.
+      let truncate = match rd.remaining() {
+        0 => None,
+        3 => Some(try!(rd.be(3))),
+        _ => return Err(CartErr::BadPatch(rd.pos)),
+      };
+
+      Ok(Patch { records, truncate })
+    }
+
+    /// Writes every record into `rom`, growing it with zeroes when a record
+    /// lands past the current end.
+    pub fn apply(&self, rom: &mut Vec<u8>) {
+      for rec in self.records.iter() {
+        let (offset, len) = match *rec {
+          Record::Data(offset, ref data) => (offset, data.len()),
+          Record::Run(offset, run, _) => (offset, run),
+        };
+        if rom.len() < offset + len {
+          rom.resize(offset + len, 0);
+        }
+
+        match *rec {
+          Record::Data(_, ref data) => rom[offset .. offset + len].copy_from_slice(data),
+          Record::Run(_, _, value) => {
+            for b in rom[offset .. offset + len].iter_mut() {
+              *b = value;
+            }
+          },
+        }
+      }
+
+      if let Some(size) = self.truncate {
+        rom.truncate(size);
+      }
+    }
+  }
+}
+
+mod sha256 {
+  use alloc::vec::Vec;
+
+  const K: [u32; 64] = [
+    0x428A2F98, 0x71374491, 0xB5C0FBCF, 0xE9B5DBA5, 0x3956C25B, 0x59F111F1, 0x923F82A4, 0xAB1C5ED5,
+    0xD807AA98, 0x12835B01, 0x243185BE, 0x550C7DC3, 0x72BE5D74, 0x80DEB1FE, 0x9BDC06A7, 0xC19BF174,
+    0xE49B69C1, 0xEFBE4786, 0x0FC19DC6, 0x240CA1CC, 0x2DE92C6F, 0x4A7484AA, 0x5CB0A9DC, 0x76F988DA,
+    0x983E5152, 0xA831C66D, 0xB00327C8, 0xBF597FC7, 0xC6E00BF3, 0xD5A79147, 0x06CA6351, 0x14292967,
+    0x27B70A85, 0x2E1B2138, 0x4D2C6DFC, 0x53380D13, 0x650A7354, 0x766A0ABB, 0x81C2C92E, 0x92722C85,
+    0xA2BFE8A1, 0xA81A664B, 0xC24B8B70, 0xC76C51A3, 0xD192E819, 0xD6990624, 0xF40E3585, 0x106AA070,
+    0x19A4C116, 0x1E376C08, 0x2748774C, 0x34B0BCB5, 0x391C0CB3, 0x4ED8AA4A, 0x5B9CCA4F, 0x682E6FF3,
+    0x748F82EE, 0x78A5636F, 0x84C87814, 0x8CC70208, 0x90BEFFFA, 0xA4506CEB, 0xBEF9A3F7, 0xC67178F2,
+  ];
+
+  const H0: [u32; 8] = [
+    0x6A09E667, 0xBB67AE85, 0x3C6EF372, 0xA54FF53A, 0x510E527F, 0x9B05688C, 0x1F83D9AB, 0x5BE0CD19,
+  ];
+
+  /// SHA-256 of `bytes`. Only ever fed a header, so the whole message is padded
+  /// in one buffer rather than streamed.
+  pub fn digest(bytes: &[u8]) -> [u8; 32] {
+    let mut msg = bytes.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+      msg.push(0);
+    }
+    let bits = (bytes.len() as u64).wrapping_mul(8);
+    msg.extend((0 .. 8).rev().map(|i| (bits >> (8 * i)) as u8));
+
+    let mut h = H0;
+    for block in msg.chunks(64) {
+      compress(&mut h, block);
+    }
+
+    let mut out = [0u8; 32];
+    for (word, chunk) in h.iter().zip(out.chunks_mut(4)) {
+      chunk.copy_from_slice(&word.to_be_bytes());
+    }
+    out
+  }
+
+  fn compress(h: &mut [u32; 8], block: &[u8]) {
+    let mut w: Vec<u32> = block.chunks(4)
+      .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+      .collect();
+    for i in 16 .. 64 {
+      let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+      let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+      let next = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+      w.push(next);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = *h;
+    for i in 0 .. 64 {
+      let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+      let ch = (e & f) ^ (!e & g);
+      let t1 = hh.wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+      let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+      let maj = (a & b) ^ (a & c) ^ (b & c);
+      let t2 = s0.wrapping_add(maj);
+      hh = g;
+      g = f;
+      f = e;
+      e = d.wrapping_add(t1);
+      d = c;
+      c = b;
+      b = a;
+      a = t1.wrapping_add(t2);
+    }
+
+    for (x, y) in h.iter_mut().zip([a, b, c, d, e, f, g, hh].iter()) {
+      *x = x.wrapping_add(*y);
+    }
+  }
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+
+  /// A ROM as large as `rom_size` declares, 32KB if unknown, with a valid logo
+  /// and checksums and `NOP; JP 0x0150` at the entry point.
+  pub fn rom(title: &str, kind: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+    let size = ROMNum::try_from(rom_size as usize)
+      .map(ROMNum::size_bytes)
+      .unwrap_or(2 * ROM_BANK_BYTES);
+    let mut bytes = vec![0; size];
+    bytes[0x100 .. 0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    bytes[regions::META_LOGO.0 .. regions::META_LOGO.1].copy_from_slice(&NINTENDO_LOGO);
+    let title_at = regions::META_TITLE.0;
+    bytes[title_at .. title_at + title.len()].copy_from_slice(title.as_bytes());
+    bytes[regions::META_COMPONENTS.0] = kind;
+    bytes[regions::META_ROM_SIZE.0] = rom_size;
+    bytes[regions::META_RAM_SIZE.0] = ram_size;
+    bytes[regions::META_LICENSEE_OLD.0] = 0x01;
+    fix_checksums(&mut bytes);
+    bytes
+  }
+
+  /// Rewrites both checksum fields to match the rest of `bytes`.
+  pub fn fix_checksums(bytes: &mut Vec<u8>) {
+    let mut rom = ROM { bytes: mem::take(bytes) };
+    rom.fix_checksums().unwrap();
+    *bytes = rom.bytes;
+  }
+
+  #[test]
+  fn patched_title_shows_after_refresh() {
+    let mut cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+
+    assert_eq!(cart.patch_byte(regions::META_TITLE.0, b'P').unwrap(), b'T');
+    assert!(cart.title().starts_with("TETRIS"));
+    cart.refresh_metadata().unwrap();
+    assert!(cart.title().starts_with("PETRIS"));
+  }
+
+  #[test]
+  fn patch_byte_rejects_offsets_past_the_end() {
+    let mut cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+    let len = 0x8000;
+
+    match cart.patch_byte(len, 0x00) {
+      Err(CartErr::OffsetOOB(offset)) => assert_eq!(offset, len),
+      x => panic!("expected OffsetOOB, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn apply_ips_writes_records_and_fixes_checksums() {
+    let mut cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+    let mut patch = Vec::from(&b"PATCH"[..]);
+    // The title, which the header checksum covers.
+    patch.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x01, b'P']);
+    patch.extend_from_slice(&[0x00, 0x01, 0x50, 0x00, 0x02, 0xAA, 0xBB]);
+    // An RLE run of four 0x77s.
+    patch.extend_from_slice(&[0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x04, 0x77]);
+    patch.extend_from_slice(b"EOF");
+
+    cart.apply_ips(&patch).unwrap();
+
+    assert!(cart.title().starts_with("PETRIS"));
+    let bytes = &cart.rom.bytes;
+    assert_eq!(&bytes[0x150 .. 0x152], &[0xAA, 0xBB]);
+    assert_eq!(&bytes[0x200 .. 0x205], &[0x77, 0x77, 0x77, 0x77, 0x00]);
+    assert!(check_header_sum(&cart.rom).is_ok());
+    let stored = (bytes[0x14E] as u16) << 8 | bytes[0x14F] as u16;
+    assert_eq!(compute_global_sum(&cart.rom).unwrap(), stored);
+  }
+
+  #[test]
+  fn failed_ips_leaves_the_cartridge_untouched() {
+    let bytes = rom("TETRIS", 0x00, 0x00, 0x00);
+    let mut cart = Cartridge::new(bytes.clone()).unwrap();
+    let mut patch = Vec::from(&b"PATCH"[..]);
+    patch.extend_from_slice(&[0x00, 0x01, 0x34, 0x00, 0x01, b'P']);
+    patch.extend_from_slice(b"EOF");
+    // Truncates the ROM to 0x100 bytes, cutting off the header.
+    patch.extend_from_slice(&[0x00, 0x01, 0x00]);
+
+    assert!(cart.apply_ips(&patch).is_err());
+    assert!(cart.title().starts_with("TETRIS"));
+    assert_eq!(cart.into_rom_bytes(), bytes);
+  }
+
+  #[test]
+  fn apply_ips_rejects_a_missing_header() {
+    let mut cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+
+    match cart.apply_ips(b"PTCH") {
+      Err(CartErr::BadPatch(0)) => {},
+      x => panic!("expected BadPatch(0), got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn diff_lists_the_changed_byte() {
+    let bytes = rom("TETRIS", 0x00, 0x00, 0x00);
+    let mut modified = bytes.clone();
+    modified[0x2000] = 0x5A;
+    let ours = Cartridge::new(bytes).unwrap();
+    let theirs = Cartridge::new(modified).unwrap();
+
+    assert_eq!(ours.diff(&theirs).unwrap(), vec![(0x2000, 0x00, 0x5A)]);
+    assert!(ours.diff(&ours).unwrap().is_empty());
+  }
+
+  #[test]
+  fn diff_requires_the_same_length() {
+    let ours = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+    let mut bytes = rom("TETRIS", 0x00, 0x00, 0x00);
+    bytes.resize(0x10000, 0x00);
+    let theirs = Cartridge::new(bytes).unwrap();
+
+    match ours.diff(&theirs) {
+      Err(CartErr::SizeMismatch(a, b)) => assert_eq!((a, b), (0x8000, 0x10000)),
+      x => panic!("expected SizeMismatch, got {:?}", x),
+    }
+  }
+
+  /// Nothing here needs `std`; `cargo test --no-default-features` runs this
+  /// against the `core` and `alloc` build.
+  #[test]
+  fn new_parses_with_alloc_only() {
+    let cart = Cartridge::new(rom("TETRIS", 0x03, 0x01, 0x02)).unwrap();
+
+    assert!(cart.title().starts_with("TETRIS"));
+    assert_eq!(cart.components(), &vec![Component::ROM(ROMNum::N4), Component::MBC(MBCNum::N1),
+                                        Component::RAM(RAMNum::N1_8kB), Component::Battery]);
+  }
+
+  #[test]
+  fn bank_counts_follow_the_header() {
+    let cart = Cartridge::new(rom("BANKS", 0x03, 0x04, 0x03)).unwrap();
+    assert_eq!(cart.rom_bank_count(), 32);
+    assert_eq!(cart.ram_bank_count(), 4);
+
+    // 2KB is a single, partially populated bank.
+    let cart = Cartridge::new(rom("BANKS", 0x03, 0x00, 0x01)).unwrap();
+    assert_eq!(cart.rom_bank_count(), 2);
+    assert_eq!(cart.ram_bank_count(), 1);
+
+    let cart = Cartridge::new(rom("BANKS", 0x00, 0x00, 0x00)).unwrap();
+    assert_eq!(cart.ram_bank_count(), 0);
+  }
+
+  #[test]
+  fn read_rom_is_bounds_checked() {
+    let cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+
+    assert_eq!(cart.read_rom(0x101), Some(0xC3));
+    assert_eq!(cart.read_rom(0x7FFF), Some(0x00));
+    assert_eq!(cart.read_rom(0x8000), None);
+    assert_eq!(cart.read_rom(usize::MAX), None);
+  }
+
+  #[test]
+  fn component_kinds_drop_sizes() {
+    let cart = Cartridge::new(rom("MBC5", 0x1B, 0x01, 0x03)).unwrap();
+    let kinds: Vec<ComponentKind> = cart.component_kinds().collect();
+
+    assert_eq!(kinds, vec![ComponentKind::ROM, ComponentKind::MBC, ComponentKind::RAM,
+                           ComponentKind::Battery]);
+    assert!(cart.has_component(Component::MBC(MBCNum::N5)));
+    assert!(cart.has_component_kind(ComponentKind::RAM));
+    assert!(!cart.has_component_kind(ComponentKind::Timer));
+  }
+
+  #[test]
+  fn type_0x13_is_mbc3_with_ram_and_battery() {
+    let cart = Cartridge::new(rom("MBC3", 0x13, 0x01, 0x03)).unwrap();
+
+    assert_eq!(cart.components(), &vec![Component::ROM(ROMNum::N4), Component::MBC(MBCNum::N3),
+                                        Component::RAM(RAMNum::N3), Component::Battery]);
+  }
+
+  #[test]
+  fn title_bytes_keep_the_padding() {
+    let cart = Cartridge::new(rom("TETRIS", 0x00, 0x00, 0x00)).unwrap();
+    assert_eq!(cart.title_bytes(), &b"TETRIS\0\0\0\0\0\0\0\0\0\0"[..]);
+
+    let mut bytes = rom("ZELDA", 0x00, 0x00, 0x00);
+    bytes[regions::META_CGB_FLAG.0] = CGB_ENHANCED;
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+    assert_eq!(cart.title_bytes(), &b"ZELDA\0\0\0\0\0\0"[..]);
+  }
+
+  #[test]
+  fn cgb_title_leaves_out_the_manufacturer_and_flag() {
+    let mut bytes = rom("POKEMON_GLD", 0x00, 0x00, 0x00);
+    bytes[regions::META_MANUFACTURER.0 .. regions::META_MANUFACTURER.1].copy_from_slice(b"AAUE");
+    bytes[regions::META_CGB_FLAG.0] = CGB_ENHANCED;
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    assert_eq!(cart.title(), "POKEMON_GLD");
+    assert_eq!(cart.title().as_bytes(), cart.title_bytes());
+  }
+
+  #[test]
+  fn describe_matches_the_header() {
+    let info = Cartridge::new(rom("POKEMON", 0x13, 0x01, 0x03)).unwrap().describe();
+
+    assert_eq!(info.title, "POKEMON");
+    assert!(!info.is_cgb);
+    assert!(!info.is_sgb);
+    assert_eq!(info.rom_size, ROMNum::N4);
+    assert_eq!(info.ram_size, RAMNum::N3);
+    assert_eq!(info.rom_banks, 4);
+    assert_eq!(info.ram_banks, 4);
+    assert_eq!(info.components, vec![Component::ROM(ROMNum::N4), Component::MBC(MBCNum::N3),
+                                     Component::RAM(RAMNum::N3), Component::Battery]);
+  }
+
+  #[test]
+  fn normalize_size_pads_to_the_next_bank() {
+    let mut bytes = rom("PAD", 0x00, 0x00, 0x00);
+    bytes.resize(33000, 0x42);
+    fix_checksums(&mut bytes);
+    let mut cart = Cartridge::new(bytes).unwrap();
+
+    assert_eq!(cart.normalize_size().unwrap(), 33000);
+    let bytes = cart.into_rom_bytes();
+    assert_eq!(bytes.len(), 48 * 1024);
+    assert!(bytes[32768 .. 33000].iter().all(|&b| b == 0x42));
+    assert!(bytes[33000 ..].iter().all(|&b| b == 0xFF));
+
+    let cart = Cartridge::new(bytes).unwrap();
+    assert!(cart.global_checksum_ok());
+  }
+
+  #[test]
+  fn normalize_size_drops_trailing_fill_only() {
+    let mut bytes = rom("TRIM", 0x00, 0x00, 0x00);
+    bytes.resize(64 * 1024, 0xFF);
+    let mut cart = Cartridge::new_no_check(bytes).unwrap();
+    assert_eq!(cart.normalize_size().unwrap(), 64 * 1024);
+    assert_eq!(cart.into_rom_bytes().len(), 32 * 1024);
+
+    let mut bytes = rom("SHORT", 0x01, 0x01, 0x00);
+    bytes.truncate(40 * 1024);
+    let mut cart = Cartridge::new_no_check(bytes).unwrap();
+    cart.normalize_size().unwrap();
+    assert!(cart.global_checksum_ok());
+    assert_eq!(cart.into_rom_bytes().len(), 64 * 1024);
+  }
+
+  #[test]
+  fn boots_despite_a_bad_global_checksum() {
+    let mut bytes = rom("BOOT", 0x00, 0x00, 0x00);
+    bytes[regions::META_CHECKSUM_ALL.0] ^= 0xFF;
+    let cart = Cartridge::new(bytes).unwrap();
+
+    assert!(!cart.global_checksum_ok());
+    assert!(cart.boots_on_hardware());
+  }
+
+  #[test]
+  fn header_digest_covers_only_the_header() {
+    let bytes = rom("DIGEST", 0x01, 0x01, 0x00);
+    let mut patched = bytes.clone();
+    patched[0x2000] = 0x42;
+
+    let a = Cartridge::new(bytes).unwrap().header_digest();
+    let b = Cartridge::new(patched).unwrap().header_digest();
+    assert_eq!(a, b);
+
+    let other = Cartridge::new(rom("DIGESU", 0x01, 0x01, 0x00)).unwrap().header_digest();
+    assert_ne!(a, other);
+  }
+
+  #[test]
+  fn sha256_matches_the_standard_vectors() {
+    let hex = |d: [u8; 32]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    assert_eq!(hex(sha256::digest(b"")),
+               "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    assert_eq!(hex(sha256::digest(b"abc")),
+               "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_eq!(hex(sha256::digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")),
+               "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+  }
+
+  #[test]
+  fn chunked_global_checksum_matches_one_shot() {
+    let mut bytes = rom("CHUNKS", 0x01, 0x01, 0x00);
+    for (i, b) in bytes.iter_mut().enumerate().skip(0x150) {
+      *b = (i * 7) as u8;
+    }
+    fix_checksums(&mut bytes);
+    let stored = (bytes[0x14E] as u16) << 8 | bytes[0x14F] as u16;
+
+    let mut one_shot = GlobalChecksum::new();
+    one_shot.update(&bytes, 0);
+    assert_eq!(one_shot.finalize(), stored);
+
+    // Pieces fed back to front, with a boundary between the two checksum bytes.
+    let mut chunked = GlobalChecksum::new();
+    let offsets: Vec<usize> = (0 .. bytes.len()).step_by(0x14F).collect();
+    for &offset in offsets.iter().rev() {
+      let end = cmp::min(offset + 0x14F, bytes.len());
+      chunked.update(&bytes[offset .. end], offset);
+    }
+    assert_eq!(chunked.finalize(), stored);
+  }
+
+  #[test]
+  fn ram_consistency_compares_type_and_size() {
+    let check = |kind, ram_size| Cartridge::new(rom("RAM", kind, 0x01, ram_size)).unwrap()
+      .ram_consistency();
+
+    assert_eq!(check(0x13, 0x03), RamConsistency::Consistent);
+    assert_eq!(check(0x11, 0x00), RamConsistency::Consistent);
+    assert_eq!(check(0x13, 0x00), RamConsistency::TypeSaysRamButSizeZero);
+    assert_eq!(check(0x11, 0x03), RamConsistency::SizeSaysRamButTypeNoRam);
+  }
+
+  #[test]
+  fn infer_ram_fills_in_a_zero_size() {
+    let bytes = rom("INFER", 0x1A, 0x01, 0x00);
+
+    let cart = Cartridge::new(bytes.clone()).unwrap();
+    assert!(cart.components().contains(&Component::RAM(RAMNum::N0)));
+    assert!(!cart.components().contains(&Component::RAM(RAMNum::N1_8kB)));
+
+    let options = CartOptions::new().infer_ram(true);
+    let cart = Cartridge::with_options(bytes, &options).unwrap();
+    assert!(cart.components().contains(&Component::RAM(RAMNum::N1_8kB)));
+    assert!(!cart.components().contains(&Component::RAM(RAMNum::N0)));
+
+    // Types without RAM get none either way.
+    let cart = Cartridge::with_options(rom("NONE", 0x19, 0x01, 0x00), &options).unwrap();
+    assert!(!cart.has_component_kind(ComponentKind::RAM));
+  }
+
+  /// Writes a zip archive of `entries` to a fresh file in the temp directory.
+  #[cfg(feature = "zip")]
+  fn write_zip(name: &str, entries: &[(&str, &[u8])]) -> ::std::path::PathBuf {
+    use std::io::Write;
+
+    let path = ::std::env::temp_dir().join(format!("gbers-{}-{}.zip", name, ::std::process::id()));
+    let mut zip = ::zip::ZipWriter::new(fs::File::create(&path).unwrap());
+    for &(entry, bytes) in entries {
+      zip.start_file(entry, ::zip::write::FileOptions::default()).unwrap();
+      zip.write_all(bytes).unwrap();
+    }
+    zip.finish().unwrap();
+    path
+  }
+
+  #[cfg(feature = "zip")]
+  #[test]
+  fn from_zip_file_loads_the_only_rom() {
+    let bytes = rom("ZIPPED", 0x00, 0x00, 0x00);
+
+    let path = write_zip("one", &[("readme.txt", b"hi"), ("game.gb", &bytes)]);
+    let cart = Cartridge::from_zip_file(&path);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(cart.unwrap().title(), "ZIPPED");
+
+    let path = write_zip("none", &[("readme.txt", b"hi")]);
+    let none = Cartridge::from_zip_file(&path);
+    fs::remove_file(&path).unwrap();
+    match none {
+      Err(CartErr::NoROMInArchive) => (),
+      x => panic!("expected NoROMInArchive, got {:?}", x),
+    }
+
+    let path = write_zip("many", &[("a.gb", &bytes), ("b.gbc", &bytes)]);
+    let many = Cartridge::from_zip_file(&path);
+    fs::remove_file(&path).unwrap();
+    match many {
+      Err(CartErr::ManyROMsInArchive(2)) => (),
+      x => panic!("expected ManyROMsInArchive, got {:?}", x),
+    }
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn histogram_counts_mnemonics_in_a_bank() {
+    let mut bytes = rom("HIST", 0x00, 0x00, 0x00);
+    // LD A,0x12 three times, LD B,C, an illegal byte, then a truncated LD A,n.
+    let code = [0x3E, 0x12, 0x3E, 0x12, 0x3E, 0x12, 0x41, 0xD3];
+    bytes[0x4000 .. 0x4000 + code.len()].copy_from_slice(&code);
+    bytes[0x7FFF] = 0x3E;
+    fix_checksums(&mut bytes);
+
+    let counts = Cartridge::new(bytes).unwrap().instruction_histogram(1);
+    assert_eq!(counts.get("LD"), Some(&4));
+    assert_eq!(counts.get("NOP"), Some(&(ROM_BANK_BYTES - code.len() - 1)));
+    assert_eq!(counts.len(), 2);
+  }
+
+  #[test]
+  fn disassemble_range_stays_inside_the_range() {
+    let mut bytes = rom("RANGE", 0x01, 0x01, 0x00);
+    // A data byte that would swallow the code if the sweep started before it.
+    bytes[0x4FFF] = 0xFA;
+    bytes[0x5000 .. 0x5006].copy_from_slice(&[0x3E, 0x01, 0xC3, 0x00, 0x50, 0xFA]);
+    // JP to itself, then JR to itself, at 0x5000 in bank 3.
+    bytes[0xD000 .. 0xD005].copy_from_slice(&[0xC3, 0x00, 0x50, 0x18, 0xFE]);
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    let lines = cart.disassemble_range(0x5000, 0x5005);
+    let lines: Vec<(usize, &str)> = lines.iter().map(|&(at, ref s)| (at, s.as_str())).collect();
+    assert_eq!(lines, vec![(0x5000, "LD A,0x01"), (0x5002, "JP 0x5000")]);
+
+    // Offsets past bank 0 are disassembled at the 0x4000-0x7FFF address the
+    // CPU sees them at, but reported as file offsets.
+    let lines = cart.disassemble_range(0xD000, 0xD005);
+    let lines: Vec<(usize, &str)> = lines.iter().map(|&(at, ref s)| (at, s.as_str())).collect();
+    assert_eq!(lines, vec![(0xD000, "JP 0x5000"), (0xD003, "JR 0x5003")]);
+    assert!(cart.disassemble_range(0x5005, 0x5005).is_empty());
+  }
+
+  #[test]
+  fn disassemble_range_rebases_each_bank() {
+    let mut bytes = rom("BANKS", 0x01, 0x02, 0x00);
+    // JR to itself, then a JP cut off by the end of bank 1.
+    bytes[0x7FFC .. 0x8000].copy_from_slice(&[0x18, 0xFE, 0xC3, 0x00]);
+    bytes[0x8000 .. 0x8002].copy_from_slice(&[0x18, 0xFE]);
+    bytes[0x14000 .. 0x14002].copy_from_slice(&[0x18, 0xFE]);
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    let lines = cart.disassemble_range(0x7FFC, 0x8002);
+    let lines: Vec<(usize, &str)> = lines.iter().map(|&(at, ref s)| (at, s.as_str())).collect();
+    assert_eq!(lines, vec![
+      (0x7FFC, "JR 0x7FFC"), (0x7FFE, "DB 0xC3"), (0x7FFF, "DB 0x00"), (0x8000, "JR 0x4000"),
+    ]);
+
+    // Longer than the 64KB the CPU can address, without repeating offsets.
+    let lines = cart.disassemble_range(0x4000, 0x14002);
+    assert!(lines.windows(2).all(|w| w[0].0 < w[1].0));
+    assert_eq!(lines.last(), Some(&(0x14000, String::from("JR 0x4000"))));
+  }
+
+  #[test]
+  fn short_rom_fails_cleanly_instead_of_panicking() {
+    let mut short = rom("SHORT", 0x00, 0x00, 0x00);
+    short.truncate(0x140);
+    match read_title(&ROM { bytes: short.clone() }, false) {
+      Err(CartErr::RegionOOB) => (),
+      x => panic!("expected RegionOOB, got {:?}", x),
+    }
+    match Cartridge::new(short) {
+      Err(CartErr::RegionOOB) => (),
+      x => panic!("expected RegionOOB, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn read_region_returns_undecoded_fields() {
+    let mut bytes = rom("VERSION", 0x00, 0x00, 0x00);
+    bytes[regions::META_VERSION.0] = 0x02;
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    assert_eq!(cart.read_region(&regions::META_VERSION).unwrap(), &[0x02]);
+    assert_eq!(cart.read_region(&regions::META_TITLE).unwrap(), cart.title_bytes());
+  }
+
+  #[test]
+  fn quick_check_looks_at_logo_and_header_checksum() {
+    let bytes = rom("QUICK", 0x00, 0x00, 0x00);
+    assert!(Cartridge::quick_check(&bytes));
+    assert!(!Cartridge::quick_check(&bytes[.. 0x14D]));
+
+    let mut bad_logo = bytes.clone();
+    bad_logo[regions::META_LOGO.0 + 3] ^= 0xFF;
+    assert!(!Cartridge::quick_check(&bad_logo));
+
+    let mut bad_sum = bytes.clone();
+    bad_sum[regions::META_TITLE.0] = b'X';
+    assert!(!Cartridge::quick_check(&bad_sum));
+
+    // The global checksum isn't looked at.
+    let mut bad_global = bytes;
+    bad_global[0x2000] = 0x42;
+    assert!(Cartridge::quick_check(&bad_global));
+  }
+
+  #[test]
+  fn sgb_border_needs_the_flag_the_licensee_and_a_pct_trn() {
+    let sgb = |flag: u8, licensee: u8, packet: bool| {
+      let mut bytes = rom("SGB", 0x00, 0x00, 0x00);
+      bytes[regions::META_SGB_FLAG.0] = flag;
+      bytes[regions::META_LICENSEE_OLD.0] = licensee;
+      // The zeroed payload comes from the blank ROM around it.
+      if packet {
+        bytes[0x2000] = SGB_PCT_TRN;
+      }
+      fix_checksums(&mut bytes);
+      Cartridge::new(bytes).unwrap().sgb_border_present()
+    };
+
+    assert!(sgb(0x03, SGB_LICENSEE, true));
+    assert!(!sgb(0x00, SGB_LICENSEE, true));
+    assert!(!sgb(0x03, 0x01, true));
+    assert!(!sgb(0x03, SGB_LICENSEE, false));
+  }
+
+  #[test]
+  fn parse_many_keeps_the_input_order() {
+    let mut bad = rom("BAD", 0x00, 0x00, 0x00);
+    bad[regions::META_CHECKSUM_HDR.0] ^= 0xFF;
+    let roms = vec![rom("ONE", 0x00, 0x00, 0x00), bad, rom("THREE", 0x01, 0x01, 0x00),
+                    Vec::new()];
+
+    let results = Cartridge::parse_many(roms);
+    let oks: Vec<bool> = results.iter().map(|r| r.is_ok()).collect();
+    assert_eq!(oks, vec![true, false, true, false]);
+    assert_eq!(results[2].as_ref().unwrap().title(), "THREE");
+  }
+
+  #[test]
+  fn bad_header_checksum_reports_the_masked_sum() {
+    let mut bytes = rom("MASKED", 0x00, 0x00, 0x00);
+    let expected = bytes[0x134 .. 0x14D].iter().fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+    assert_eq!(bytes[regions::META_CHECKSUM_HDR.0], expected);
+    bytes[regions::META_CHECKSUM_HDR.0] = expected ^ 0xFF;
+
+    match Cartridge::new(bytes) {
+      Err(CartErr::BadHeaderChecksum(computed, stored)) => {
+        assert_eq!(computed, expected);
+        assert_eq!(stored, expected ^ 0xFF);
+      },
+      x => panic!("expected BadHeaderChecksum, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn mbc_register_ranges_follow_the_mapper() {
+    let ranges = |kind| Cartridge::new(rom("RANGES", kind, 0x01, 0x00)).unwrap()
+      .mbc_register_ranges();
+
+    assert_eq!(ranges(0x01), vec![
+      (0x0000 ..= 0x1FFF, MBCRegister::RAMEnable),
+      (0x2000 ..= 0x3FFF, MBCRegister::ROMBank),
+      (0x4000 ..= 0x5FFF, MBCRegister::RAMBank),
+      (0x6000 ..= 0x7FFF, MBCRegister::Mode),
+    ]);
+    assert_eq!(ranges(0x19), vec![
+      (0x0000 ..= 0x1FFF, MBCRegister::RAMEnable),
+      (0x2000 ..= 0x2FFF, MBCRegister::ROMBank),
+      (0x3000 ..= 0x3FFF, MBCRegister::ROMBankHigh),
+      (0x4000 ..= 0x5FFF, MBCRegister::RAMBank),
+    ]);
+    assert!(ranges(0x00).is_empty());
+  }
+
+  #[test]
+  fn canonical_names_carry_region_and_revision() {
+    let name = |title, dest, version| {
+      let mut bytes = rom(title, 0x00, 0x00, 0x00);
+      bytes[regions::META_DEST.0] = dest;
+      bytes[regions::META_VERSION.0] = version;
+      fix_checksums(&mut bytes);
+      Cartridge::new(bytes).unwrap().canonical_name()
+    };
+
+    assert_eq!(name("POKEMON RED", 0x00, 0x01), "POKEMON RED (Japan) (Rev A)");
+    assert_eq!(name("POKEMON RED", 0x01, 0x00), "POKEMON RED (World)");
+    assert_eq!(name("", 0x01, 0x00), "Unknown (World)");
+  }
+
+  #[test]
+  fn from_parts_skips_the_header() {
+    let components = vec![Component::ROM(ROMNum::N4), Component::MBC(MBCNum::N5),
+                          Component::RAM(RAMNum::N3), Component::Battery];
+    let cart = Cartridge::from_parts("SYNTH", components.clone(), true, false,
+                                     vec![0; 4 * ROM_BANK_BYTES]).unwrap();
+
+    assert_eq!(cart.title(), "SYNTH");
+    assert_eq!(cart.components(), &components[..]);
+    assert_eq!(cart.mbc(), Some(MBCNum::N5));
+    assert_eq!(cart.rom_bank_count(), 4);
+    assert_eq!(cart.ram_bank_count(), 4);
+    assert!(cart.describe().is_cgb);
+    assert!(!cart.describe().is_sgb);
+  }
+
+  #[test]
+  fn from_parts_rejects_inconsistent_components() {
+    // A timer needs MBC3.
+    let components = vec![Component::ROM(ROMNum::N2), Component::MBC(MBCNum::N1),
+                          Component::Timer];
+    match Cartridge::from_parts("BAD", components, false, false, vec![0; 0x8000]) {
+      Err(CartErr::InconsistentComponents(Component::Timer)) => (),
+      x => panic!("expected InconsistentComponents(Timer), got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn component_ids_round_trip() {
+    let mut all = vec![Component::Battery, Component::MMM, Component::SRAM, Component::Timer,
+                       Component::RTC, Component::Rumble, Component::PocketCam,
+                       Component::BandaiTAMA5, Component::HudsonHUC1, Component::HudsonHUC3];
+    all.extend([MBCNum::N1, MBCNum::N2, MBCNum::N3, MBCNum::N5].iter().cloned().map(Component::MBC));
+    all.extend((0 .. 0x100).filter_map(|n| ROMNum::try_from(n).ok()).map(Component::ROM));
+    all.extend((0 .. 0x100).filter_map(|n| RAMNum::try_from(n).ok()).map(Component::RAM));
+    assert_eq!(all.len(), 10 + 4 + 10 + 5);
+
+    for c in all.iter() {
+      assert_eq!(Component::from_id(c.id()).as_ref(), Some(c));
+    }
+    let mut ids: Vec<u16> = all.iter().map(Component::id).collect();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), all.len());
+
+    assert_eq!(Component::from_id(0x0104), None);
+    assert_eq!(Component::from_id(0x0201), None);
+    assert_eq!(Component::from_id(0xFF00), None);
+  }
+
+  #[test]
+  fn mbc3_timer_carts_depend_on_the_timer_interrupt() {
+    let cart = Cartridge::new(rom("CLOCK", 0x0F, 0x01, 0x00)).unwrap();
+    assert_eq!(cart.possible_interrupt_sources(), vec![Interrupt::Timer]);
+
+    let cart = Cartridge::new(rom("PLAIN", 0x13, 0x01, 0x03)).unwrap();
+    assert!(cart.possible_interrupt_sources().is_empty());
+  }
+
+  #[test]
+  fn multi_byte_header_fields_read_in_their_byte_order() {
+    let mut bytes = rom("LE", 0x00, 0x00, 0x00);
+    bytes[regions::META_MANUFACTURER.0 .. regions::META_MANUFACTURER.1].copy_from_slice(b"ABCD");
+    bytes[regions::META_LICENSEE.0 .. regions::META_LICENSEE.1].copy_from_slice(b"01");
+    fix_checksums(&mut bytes);
+    let (hi, lo) = (bytes[0x14E], bytes[0x14F]);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    assert_eq!(cart.manufacturer_code(), Some(0x4443_4241));
+    assert_eq!(cart.licensee_code(), Some(0x3130));
+    assert_eq!(cart.rom.read_u16_be(0x14E).unwrap(), (hi as u16) << 8 | lo as u16);
+    assert!(cart.global_checksum_ok());
+
+    let short = ROM { bytes: vec![0x01, 0x02, 0x03] };
+    assert_eq!(short.read_u16_le(1).unwrap(), 0x0302);
+    match short.read_u32_le(0) {
+      Err(CartErr::OffsetOOB(0)) => (),
+      x => panic!("expected OffsetOOB(0), got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn title_region_shrinks_on_cgb_cartridges() {
+    let dmg = Cartridge::new(rom("DMG", 0x00, 0x00, 0x00)).unwrap();
+    let region = dmg.title_region();
+    assert_eq!((region.0, region.1), (0x134, 0x144));
+
+    let mut bytes = rom("CGB", 0x00, 0x00, 0x00);
+    bytes[regions::META_CGB_FLAG.0] = CGB_ENHANCED;
+    fix_checksums(&mut bytes);
+    let cgb = Cartridge::new(bytes).unwrap();
+    let region = cgb.title_region();
+    assert_eq!((region.0, region.1), (0x134, 0x13F));
+    assert!(region.1 <= regions::META_CGB_FLAG.0);
+  }
+
+  #[test]
+  fn empty_input_is_too_small() {
+    match Cartridge::new(Vec::new()) {
+      Err(CartErr::TooSmall(0)) => (),
+      x => panic!("expected TooSmall(0), got {:?}", x),
+    }
+    match Cartridge::new_no_check(Vec::new()) {
+      Err(CartErr::TooSmall(0)) => (),
+      x => panic!("expected TooSmall(0), got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn mbc3_timer_types_carry_an_rtc() {
+    let cart = Cartridge::new(rom("GOLD", 0x10, 0x01, 0x03)).unwrap();
+    assert_eq!(cart.components(), &vec![Component::ROM(ROMNum::N4), Component::MBC(MBCNum::N3),
+                                        Component::Timer, Component::RTC,
+                                        Component::RAM(RAMNum::N3), Component::Battery]);
+    assert!(cart.has_component_kind(ComponentKind::RTC));
+
+    let cart = Cartridge::new(rom("PLAIN", 0x13, 0x01, 0x03)).unwrap();
+    assert!(!cart.has_component_kind(ComponentKind::RTC));
+  }
+
+  #[test]
+  fn same_hardware_hashes_the_same() {
+    let a = Cartridge::new(rom("POKEMON", 0x1B, 0x01, 0x03)).unwrap();
+    let b = Cartridge::new(rom("ZELDA", 0x1B, 0x02, 0x02)).unwrap();
+    assert_eq!(a.components_hash(), b.components_hash());
+
+    let c = Cartridge::new(rom("TETRIS", 0x1A, 0x01, 0x03)).unwrap();
+    assert_ne!(a.components_hash(), c.components_hash());
+  }
+
+  #[test]
+  fn header_anomalies_flag_rebuilt_headers() {
+    let anomalies = |bytes: Vec<u8>| Cartridge::new_no_check(bytes).unwrap().header_anomalies();
+
+    assert_eq!(anomalies(rom("CLEAN", 0x00, 0x00, 0x00)), vec![]);
+
+    let mut zeroed = rom("ZEROED", 0x00, 0x00, 0x00);
+    zeroed[regions::META_CHECKSUM_ALL.0 .. regions::META_CHECKSUM_ALL.1].copy_from_slice(&[0, 0]);
+    assert_eq!(anomalies(zeroed), vec![Anomaly::ZeroGlobalChecksum]);
+
+    let mut unlicensed = rom("HOMEBREW", 0x00, 0x00, 0x00);
+    unlicensed[regions::META_LICENSEE_OLD.0] = 0x00;
+    fix_checksums(&mut unlicensed);
+    assert_eq!(anomalies(unlicensed), vec![Anomaly::NoLicensee]);
+
+    let mut logo = rom("LOGO", 0x00, 0x00, 0x00);
+    logo[regions::META_LOGO.0] ^= 0xFF;
+    assert_eq!(anomalies(logo), vec![Anomaly::BadLogo, Anomaly::BadGlobalChecksum]);
+
+    let mut header = rom("HEADER", 0x00, 0x00, 0x00);
+    header[regions::META_CHECKSUM_HDR.0] ^= 0xFF;
+    assert_eq!(anomalies(header), vec![Anomaly::BadHeaderChecksum, Anomaly::BadGlobalChecksum]);
+
+    let mut both = rom("BOTH", 0x00, 0x00, 0x00);
+    both[regions::META_LOGO.0] ^= 0xFF;
+    both[regions::META_CHECKSUM_HDR.0] ^= 0xFF;
+    assert_eq!(anomalies(both), vec![
+      Anomaly::BadLogo,
+      Anomaly::BadHeaderChecksum,
+      Anomaly::BadGlobalChecksum,
+    ]);
+
+    let mut sizes = rom("SIZES", 0x13, 0x01, 0x00);
+    sizes.truncate(0x8000);
+    fix_checksums(&mut sizes);
+    assert_eq!(anomalies(sizes), vec![
+      Anomaly::RomSizeMismatch,
+      Anomaly::RamMismatch(RamConsistency::TypeSaysRamButSizeZero),
+    ]);
+  }
+
+  #[test]
+  fn into_rom_bytes_hands_back_the_input() {
+    let bytes = rom("OWNED", 0x01, 0x01, 0x00);
+    let cart = Cartridge::new(bytes.clone()).unwrap();
+    assert_eq!(cart.into_rom_bytes(), bytes);
+  }
+
+  #[test]
+  fn mmm01_games_are_found_behind_the_menu() {
+    // Two 32KB games, 32KB of filler, then the menu, whose header declares MMM01.
+    let mut bytes = rom("GAME ONE", 0x00, 0x02, 0x00);
+    bytes[0x8000 .. 0x10000].copy_from_slice(&rom("GAME TWO", 0x00, 0x00, 0x00));
+    bytes[0x18000 ..].copy_from_slice(&rom("MENU", 0x0B, 0x00, 0x00));
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+
+    assert_eq!(cart.title(), "GAME ONE");
+    assert!(cart.is_mmm01());
+    assert_eq!(cart.mmm01_game_offsets(), vec![0x0000, 0x8000]);
+
+    let plain = Cartridge::new(rom("PLAIN", 0x00, 0x02, 0x00)).unwrap();
+    assert!(!plain.is_mmm01());
+    assert!(plain.mmm01_game_offsets().is_empty());
+  }
+
+  #[test]
+  fn bank_mask_and_the_modulo_fallback() {
+    let cart = Cartridge::new(rom("SIXTEEN", 0x19, 0x03, 0x00)).unwrap();
+    assert_eq!(cart.rom_bank_count(), 16);
+    assert_eq!(cart.rom_bank_mask(), 0x0F);
+
+    let cart = Cartridge::new(rom("SEVENTYTWO", 0x19, 0x52, 0x00)).unwrap();
+    assert_eq!(cart.rom_bank_count(), 72);
+    assert_eq!(cart.rom_bank_mask(), 0x7F);
+
+    // Bank 80 passes the mask but is past the end; the MBC wraps it to bank 8.
+    let mut mbc = mbc::MBC::new(cart.mbc(), cart.effective_rom_size());
+    mbc.write(0x2000, 80);
+    assert_eq!(80 & cart.rom_bank_mask(), 80);
+    assert_eq!(mbc.rom_offset(0x4000), 8 * ROM_BANK_BYTES);
+    mbc.write(0x2000, 71);
+    assert_eq!(mbc.rom_offset(0x4000), 71 * ROM_BANK_BYTES);
+  }
+
+  #[test]
+  fn sizes_display_in_binary_units() {
+    assert_eq!(format!("{}", ROMNum::N2), "32 KiB");
+    assert_eq!(format!("{}", ROMNum::N32), "512 KiB");
+    assert_eq!(format!("{}", ROMNum::N72), "1152 KiB");
+    assert_eq!(format!("{}", ROMNum::N128), "2 MiB");
+    assert_eq!(format!("{}", RAMNum::N0), "0 KiB");
+    assert_eq!(format!("{}", RAMNum::N1_2kB), "2 KiB");
+    assert_eq!(format!("{}", RAMNum::N3), "32 KiB");
+    assert_eq!(format!("{}", RAMNum::N4), "128 KiB");
+  }
+
+  #[test]
+  fn recording_constructor_loads_and_reports_bad_checksums() {
+    let mut bytes = rom("BADSUM", 0x00, 0x00, 0x00);
+    let header_at = regions::META_CHECKSUM_HDR.0;
+    let good = bytes[header_at];
+    bytes[header_at] = good.wrapping_add(1);
+    match Cartridge::new(bytes.clone()) {
+      Err(CartErr::BadHeaderChecksum(..)) => (),
+      x => panic!("expected BadHeaderChecksum, got {:?}", x),
+    }
+
+    let cart = Cartridge::new_recording_checksum(bytes).unwrap();
+    assert_eq!(cart.title(), "BADSUM");
+    let status = cart.checksum_status().unwrap();
+    assert!(!status.header_ok());
+    assert_eq!(status.header, (good, good.wrapping_add(1)));
+    // The global sum covers the header checksum byte too, so it no longer
+    // matches either.
+    assert!(!status.global_ok());
+
+    let cart = Cartridge::new_recording_checksum(rom("GOODSUM", 0x00, 0x00, 0x00)).unwrap();
+    let status = cart.checksum_status().unwrap();
+    assert!(status.header_ok() && status.global_ok());
+    assert_eq!(Cartridge::new(rom("GOODSUM", 0x00, 0x00, 0x00)).unwrap().checksum_status(), None);
+  }
+
+  #[test]
+  fn odd_rom_sizes_have_their_full_bank_counts() {
+    for &(code, banks) in &[(0x52, 72), (0x53, 80), (0x54, 96)] {
+      let cart = Cartridge::new(rom("ODDSIZE", 0x19, code, 0x00)).unwrap();
+      assert_eq!(cart.rom_bank_count(), banks);
+      assert_eq!(cart.rom_banks().count(), banks);
+    }
+    assert_eq!(ROMNum::N72.size_bytes() / ROM_BANK_BYTES, 72);
+  }
+
+  #[test]
+  fn supported_models_follow_the_cgb_flag() {
+    let with_flag = |flag: u8| {
+      let mut bytes = rom("MODELS", 0x00, 0x00, 0x00);
+      bytes[regions::META_CGB_FLAG.0] = flag;
+      fix_checksums(&mut bytes);
+      Cartridge::new(bytes).unwrap()
+    };
+
+    assert_eq!(with_flag(0x00).supported_models(), vec![Model::DMG, Model::MGB]);
+    assert_eq!(with_flag(CGB_ENHANCED).supported_models(),
+               vec![Model::DMG, Model::MGB, Model::CGB]);
+    assert_eq!(with_flag(CGB_ONLY).supported_models(), vec![Model::CGB]);
+  }
+
+  #[test]
+  fn load_collects_every_soft_issue() {
+    let mut bytes = rom("SOFT", 0x0A, 0x00, 0x00);
+    bytes[regions::META_LOGO.0] ^= 0xFF;
+    let header_at = regions::META_CHECKSUM_HDR.0;
+    let good = bytes[header_at];
+    bytes[header_at] = good ^ 0xFF;
+    bytes.extend(vec![0; ROM_BANK_BYTES]);
+
+    let (cart, warnings) = Cartridge::load(bytes).unwrap();
+    assert_eq!(cart.title(), "SOFT");
+    assert_eq!(warnings.len(), 5, "{:?}", warnings);
+    assert!(warnings.contains(&Warning::UnknownComponents(0x0A)));
+    assert!(warnings.contains(&Warning::BadLogo));
+    assert!(warnings.contains(&Warning::BadHeaderChecksum(good, good ^ 0xFF)));
+    assert!(warnings.contains(&Warning::SizeMismatch(2 * ROM_BANK_BYTES, 3 * ROM_BANK_BYTES)));
+    assert!(warnings.iter().any(|w| matches!(*w, Warning::BadGlobalChecksum(..))));
+    assert!(!cart.checksum_status().unwrap().header_ok());
+
+    let (_, warnings) = Cartridge::load(rom("CLEAN", 0x00, 0x00, 0x00)).unwrap();
+    assert!(warnings.is_empty(), "{:?}", warnings);
   }
 }
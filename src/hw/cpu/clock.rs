@@ -15,19 +15,52 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use core::result;
 
-const CYCLE_INCREMENT: usize = 4;
+use hw::model::Model;
+
+
+/// T-cycles in one M-cycle.
+pub const CYCLE_INCREMENT: usize = 4;
 
 pub struct Clock {
   freq: Frequency,
   time: usize,
+  /// The model being clocked, if known. Only a CGB may run at double speed.
+  model: Option<Model>,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Frequency {
   Single,
   Double
 }
 
+/// The timing captured by `Clock::save_state`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClockState {
+  pub time: usize,
+  pub freq: Frequency,
+}
+
+impl Frequency {
+  /// T-cycles per second at this speed.
+  pub const fn hz(&self) -> u32 {
+    match *self {
+      Frequency::Single => 4_194_304,
+      Frequency::Double => 8_388_608,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClockErr {
+  /// This model has no double-speed mode.
+  NoDoubleSpeed(Model),
+}
+
 impl Clock {
 
   pub fn new(freq: Frequency) -> Clock {
@@ -38,9 +71,18 @@ impl Clock {
     Clock {
       time,
       freq,
+      model: None,
     }
   }
 
+  /// A clock at the speed `model` starts in. Only a CGB clock will accept a
+  /// switch to double speed.
+  pub fn for_model(model: Model) -> Clock {
+    let mut clock = Clock::new(Frequency::Single);
+    clock.model = Some(model);
+    clock
+  }
+
   pub fn incr(&mut self) {
     self.time += CYCLE_INCREMENT;
   }
@@ -53,4 +95,126 @@ impl Clock {
     self.time
   }
 
+  /// Rewinds to time 0, keeping the current frequency.
+  pub fn reset(&mut self) {
+    self.time = 0;
+  }
+
+  /// How many T-cycles into the current M-cycle the clock is.
+  pub fn t_cycle_phase(&self) -> usize {
+    self.time % CYCLE_INCREMENT
+  }
+
+  /// Advances to the start of the next M-cycle, unless already on one.
+  pub fn align_to_m_cycle(&mut self) {
+    let phase = self.t_cycle_phase();
+    if phase != 0 {
+      self.time += CYCLE_INCREMENT - phase;
+    }
+  }
+
+  pub fn save_state(&self) -> ClockState {
+    ClockState {
+      time: self.time,
+      freq: self.freq,
+    }
+  }
+
+  /// Restores time and speed from `state`. The model is kept, and isn't
+  /// checked against the restored speed.
+  pub fn restore_state(&mut self, state: ClockState) {
+    self.time = state.time;
+    self.freq = state.freq;
+  }
+
+  pub fn frequency(&self) -> Frequency {
+    self.freq
+  }
+
+  /// Fails without changing speed if asked for double speed on a clock made
+  /// for a model other than the CGB.
+  pub fn set_frequency(&mut self, freq: Frequency) -> result::Result<(), ClockErr> {
+    match (freq, self.model) {
+      (Frequency::Double, Some(model)) if model != Model::CGB => Err(ClockErr::NoDoubleSpeed(model)),
+      _ => {
+        self.freq = freq;
+        Ok(())
+      },
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn phase_is_the_offset_into_the_m_cycle() {
+    for (time, phase) in [(0, 0), (1, 1), (6, 2), (7, 3), (8, 0)].iter().cloned() {
+      assert_eq!(Clock::new_start_time(time, Frequency::Single).t_cycle_phase(), phase);
+    }
+  }
+
+  #[test]
+  fn align_moves_to_the_next_m_cycle() {
+    let mut clock = Clock::new_start_time(5, Frequency::Single);
+    clock.align_to_m_cycle();
+    assert_eq!(clock.time(), 8);
+    assert_eq!(clock.t_cycle_phase(), 0);
+
+    clock.align_to_m_cycle();
+    assert_eq!(clock.time(), 8);
+
+    clock.incr();
+    assert_eq!(clock.time(), 12);
+  }
+
+  #[test]
+  fn reset_rewinds_time_but_keeps_the_speed() {
+    let mut clock = Clock::new(Frequency::Single);
+    clock.set_frequency(Frequency::Double).unwrap();
+    clock.incr_n(10);
+
+    clock.reset();
+    assert_eq!(clock.time(), 0);
+    assert_eq!(clock.frequency(), Frequency::Double);
+  }
+
+  #[test]
+  fn only_a_cgb_clock_goes_double_speed() {
+    for &model in [Model::DMG, Model::MGB, Model::SGB].iter() {
+      let mut clock = Clock::for_model(model);
+      assert_eq!(clock.frequency(), Frequency::Single);
+      assert_eq!(clock.set_frequency(Frequency::Double), Err(ClockErr::NoDoubleSpeed(model)));
+      assert_eq!(clock.frequency(), Frequency::Single);
+    }
+
+    let mut clock = Clock::for_model(Model::CGB);
+    assert_eq!(clock.frequency(), Frequency::Single);
+    clock.set_frequency(Frequency::Double).unwrap();
+    assert_eq!(clock.frequency(), Frequency::Double);
+  }
+
+  #[test]
+  fn frequencies_in_hz() {
+    assert_eq!(Frequency::Single.hz(), 4_194_304);
+    assert_eq!(Frequency::Double.hz(), 8_388_608);
+  }
+
+  #[test]
+  fn state_round_trips() {
+    let mut clock = Clock::for_model(Model::CGB);
+    clock.set_frequency(Frequency::Double).unwrap();
+    clock.incr_n(5);
+    let state = clock.save_state();
+    assert_eq!(state, ClockState { time: 20, freq: Frequency::Double });
+
+    clock.reset();
+    clock.set_frequency(Frequency::Single).unwrap();
+    clock.restore_state(state);
+    assert_eq!(clock.time(), 20);
+    assert_eq!(clock.frequency(), Frequency::Double);
+    assert_eq!(clock.save_state(), state);
+  }
 }
@@ -0,0 +1,380 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use hw::bus::Bus;
+
+use super::exec;
+use super::instr::{Cond, Immediate, Instr, Opcode, Operand, R16, R8};
+
+/// Renders decoded instructions as assembly text.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Disassembler {
+  symbols: bool,
+}
+
+impl Disassembler {
+
+  pub fn new() -> Disassembler {
+    Disassembler::default()
+  }
+
+  /// Whether addresses of known hardware registers render by name, e.g.
+  /// `LDH (LCDC),A` rather than `LDH (0xFF40),A`. Off by default.
+  pub fn with_symbols(mut self, symbols: bool) -> Disassembler {
+    self.symbols = symbols;
+    self
+  }
+
+  /// Formats `instr`, which was decoded from address `pc`. Relative jumps are
+  /// shown as their target address.
+  pub fn format(&self, instr: &Instr, pc: u16) -> String {
+    let (opcode, displace, immed) = match *instr {
+      Instr::Single { opcode, displace, immed, .. } => (opcode, displace, immed),
+      Instr::Illegal(op) => return format!("DB 0x{:02X}", op),
+    };
+    let ctx = Operands {
+      dis: self,
+      displace: displace.unwrap_or(0),
+      immed,
+      next: pc.wrapping_add(instr.len() as u16),
+    };
+
+    let args = match opcode {
+      Opcode::LD(dst, src) => vec![ctx.operand(dst), ctx.operand(src)],
+      Opcode::LDH(dst, src) => vec![ctx.operand(dst), ctx.operand(src)],
+      Opcode::PUSH(rr) => vec![String::from(r16(rr))],
+      Opcode::POP(rr) => vec![String::from(r16(rr))],
+      // The offset is a signed displacement, not an unsigned immediate.
+      Opcode::ADD(Operand::Reg16(R16::SP), _) => vec![String::from("SP"), ctx.offset()],
+      Opcode::ADD(dst, src) => vec![ctx.operand(dst), ctx.operand(src)],
+      Opcode::ADC(src) => vec![String::from("A"), ctx.operand(src)],
+      Opcode::SUB(src) => vec![ctx.operand(src)],
+      Opcode::SBC(src) => vec![String::from("A"), ctx.operand(src)],
+      Opcode::AND(src) => vec![ctx.operand(src)],
+      Opcode::XOR(src) => vec![ctx.operand(src)],
+      Opcode::OR(src) => vec![ctx.operand(src)],
+      Opcode::CP(src) => vec![ctx.operand(src)],
+      Opcode::INC(op) => vec![ctx.operand(op)],
+      Opcode::DEC(op) => vec![ctx.operand(op)],
+      Opcode::JR(cc) => with_cond(cc, ctx.jr_target()),
+      Opcode::JP(cc, op) => with_cond(cc, ctx.operand(op)),
+      Opcode::CALL(cc) => with_cond(cc, ctx.operand(Operand::Imm16)),
+      Opcode::RET(cc) => cc.map(|c| String::from(cond(c))).into_iter().collect(),
+      Opcode::RST(n) => vec![format!("0x{:02X}", n)],
+      Opcode::RLC(op) => vec![ctx.operand(op)],
+      Opcode::RRC(op) => vec![ctx.operand(op)],
+      Opcode::RL(op) => vec![ctx.operand(op)],
+      Opcode::RR(op) => vec![ctx.operand(op)],
+      Opcode::SLA(op) => vec![ctx.operand(op)],
+      Opcode::SRA(op) => vec![ctx.operand(op)],
+      Opcode::SWAP(op) => vec![ctx.operand(op)],
+      Opcode::SRL(op) => vec![ctx.operand(op)],
+      Opcode::BIT(b, op) => vec![format!("{}", b), ctx.operand(op)],
+      Opcode::RES(b, op) => vec![format!("{}", b), ctx.operand(op)],
+      Opcode::SET(b, op) => vec![format!("{}", b), ctx.operand(op)],
+      Opcode::NOP | Opcode::STOP | Opcode::HALT | Opcode::DI | Opcode::EI | Opcode::RETI
+      | Opcode::DAA | Opcode::CPL | Opcode::SCF | Opcode::CCF
+      | Opcode::RLCA | Opcode::RRCA | Opcode::RLA | Opcode::RRA => vec![],
+    };
+
+    let name = opcode.mnemonic();
+    if args.is_empty() {
+      String::from(name)
+    } else {
+      format!("{} {}", name, args.join(","))
+    }
+  }
+
+  /// Decodes and formats `bytes` from start to end, as if loaded at `base`.
+  /// A truncated instruction at the end is rendered as raw bytes.
+  pub fn disassemble(&self, bytes: &[u8], base: u16) -> Vec<(u16, String)> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at < bytes.len() {
+      let pc = base.wrapping_add(at as u16);
+      match Instr::decode(&bytes[at..]) {
+        Ok(instr) => {
+          out.push((pc, self.format(&instr, pc)));
+          at += instr.len();
+        },
+        Err(_) => {
+          for (i, &b) in bytes[at..].iter().enumerate() {
+            out.push((base.wrapping_add((at + i) as u16), format!("DB 0x{:02X}", b)));
+          }
+          break;
+        },
+      }
+    }
+    out
+  }
+
+  fn address(&self, addr: u16) -> String {
+    match io_register_name(addr) {
+      Some(name) if self.symbols => String::from(name),
+      _ => format!("0x{:04X}", addr),
+    }
+  }
+
+}
+
+/// Decodes instructions one after another straight off a bus, so it sees
+/// whatever banks are mapped in right now. Ends if an instruction can't be
+/// decoded.
+pub struct InstrStream<'a, B: Bus + ?Sized + 'a> {
+  bus: &'a mut B,
+  pc: u16,
+}
+
+impl<'a, B: Bus + ?Sized> InstrStream<'a, B> {
+
+  pub fn new(bus: &'a mut B, start: u16) -> InstrStream<'a, B> {
+    InstrStream {
+      bus,
+      pc: start,
+    }
+  }
+
+}
+
+impl<'a, B: Bus + ?Sized> Iterator for InstrStream<'a, B> {
+  type Item = (u16, Instr);
+
+  fn next(&mut self) -> Option<(u16, Instr)> {
+    let pc = self.pc;
+    let instr = exec::fetch(self.bus, pc).ok()?;
+    self.pc = pc.wrapping_add(instr.len() as u16);
+    Some((pc, instr))
+  }
+}
+
+/// The documented name of the I/O register at `addr`, if it has one.
+pub fn io_register_name(addr: u16) -> Option<&'static str> {
+  let name = match addr {
+    0xFF00 => "P1",
+    0xFF01 => "SB",
+    0xFF02 => "SC",
+    0xFF04 => "DIV",
+    0xFF05 => "TIMA",
+    0xFF06 => "TMA",
+    0xFF07 => "TAC",
+    0xFF0F => "IF",
+    0xFF10 => "NR10",
+    0xFF11 => "NR11",
+    0xFF12 => "NR12",
+    0xFF13 => "NR13",
+    0xFF14 => "NR14",
+    0xFF16 => "NR21",
+    0xFF17 => "NR22",
+    0xFF18 => "NR23",
+    0xFF19 => "NR24",
+    0xFF1A => "NR30",
+    0xFF1B => "NR31",
+    0xFF1C => "NR32",
+    0xFF1D => "NR33",
+    0xFF1E => "NR34",
+    0xFF20 => "NR41",
+    0xFF21 => "NR42",
+    0xFF22 => "NR43",
+    0xFF23 => "NR44",
+    0xFF24 => "NR50",
+    0xFF25 => "NR51",
+    0xFF26 => "NR52",
+    0xFF40 => "LCDC",
+    0xFF41 => "STAT",
+    0xFF42 => "SCY",
+    0xFF43 => "SCX",
+    0xFF44 => "LY",
+    0xFF45 => "LYC",
+    0xFF46 => "DMA",
+    0xFF47 => "BGP",
+    0xFF48 => "OBP0",
+    0xFF49 => "OBP1",
+    0xFF4A => "WY",
+    0xFF4B => "WX",
+    0xFF4D => "KEY1",
+    0xFF4F => "VBK",
+    0xFF51 => "HDMA1",
+    0xFF52 => "HDMA2",
+    0xFF53 => "HDMA3",
+    0xFF54 => "HDMA4",
+    0xFF55 => "HDMA5",
+    0xFF56 => "RP",
+    0xFF68 => "BCPS",
+    0xFF69 => "BCPD",
+    0xFF6A => "OCPS",
+    0xFF6B => "OCPD",
+    0xFF70 => "SVBK",
+    0xFFFF => "IE",
+    _ => return None,
+  };
+  Some(name)
+}
+
+/// What an operand needs from the instruction around it to be rendered.
+struct Operands<'a> {
+  dis: &'a Disassembler,
+  displace: i8,
+  immed: Option<Immediate>,
+  /// Address of the following instruction.
+  next: u16,
+}
+
+impl<'a> Operands<'a> {
+
+  fn operand(&self, op: Operand) -> String {
+    match op {
+      Operand::Reg8(r) => String::from(r8(r)),
+      Operand::Reg16(rr) => String::from(r16(rr)),
+      Operand::Indirect(rr) => format!("({})", r16(rr)),
+      Operand::IndirectInc => String::from("(HL+)"),
+      Operand::IndirectDec => String::from("(HL-)"),
+      Operand::HighC => String::from("(C)"),
+      Operand::Imm8 => format!("0x{:02X}", self.imm8()),
+      Operand::Imm16 => format!("0x{:04X}", self.imm16()),
+      Operand::Absolute => format!("({})", self.dis.address(self.imm16())),
+      Operand::HighImm8 => format!("({})", self.dis.address(0xFF00 | self.imm8() as u16)),
+      Operand::SPOffset => format!("SP{}", self.offset()),
+    }
+  }
+
+  fn offset(&self) -> String {
+    format!("{:+}", self.displace)
+  }
+
+  fn jr_target(&self) -> String {
+    format!("0x{:04X}", self.next.wrapping_add(self.displace as i16 as u16))
+  }
+
+  fn imm8(&self) -> u8 {
+    match self.immed {
+      Some(Immediate::One(n)) => n,
+      _ => 0,
+    }
+  }
+
+  fn imm16(&self) -> u16 {
+    match self.immed {
+      Some(Immediate::Two(nn)) => nn,
+      _ => 0,
+    }
+  }
+
+}
+
+fn with_cond(cc: Option<Cond>, target: String) -> Vec<String> {
+  match cc {
+    Some(c) => vec![String::from(cond(c)), target],
+    None => vec![target],
+  }
+}
+
+fn r8(r: R8) -> &'static str {
+  match r {
+    R8::A => "A",
+    R8::B => "B",
+    R8::C => "C",
+    R8::D => "D",
+    R8::E => "E",
+    R8::H => "H",
+    R8::L => "L",
+  }
+}
+
+fn r16(rr: R16) -> &'static str {
+  match rr {
+    R16::AF => "AF",
+    R16::BC => "BC",
+    R16::DE => "DE",
+    R16::HL => "HL",
+    R16::SP => "SP",
+  }
+}
+
+fn cond(c: Cond) -> &'static str {
+  match c {
+    Cond::NZ => "NZ",
+    Cond::Z => "Z",
+    Cond::NC => "NC",
+    Cond::C => "C",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hw::bus::tests::FlatBus;
+  use hw::cart::Cartridge;
+  use hw::cart::tests::{fix_checksums, rom};
+  use hw::mmu::MMU;
+  use super::*;
+
+  fn render(dis: Disassembler, bytes: &[u8]) -> String {
+    dis.format(&Instr::decode(bytes).unwrap(), 0x0150)
+  }
+
+  #[test]
+  fn io_registers_render_by_name_when_asked() {
+    let named = Disassembler::new().with_symbols(true);
+    assert_eq!(render(named, &[0xE0, 0x40]), "LDH (LCDC),A");
+    assert_eq!(render(named, &[0xF0, 0x44]), "LDH A,(LY)");
+    assert_eq!(render(named, &[0xFA, 0x41, 0xFF]), "LD A,(STAT)");
+    assert_eq!(render(named, &[0xFA, 0x00, 0xC0]), "LD A,(0xC000)");
+
+    let plain = Disassembler::new();
+    assert_eq!(render(plain, &[0xE0, 0x40]), "LDH (0xFF40),A");
+    assert_eq!(io_register_name(0xFF0F), Some("IF"));
+    assert_eq!(io_register_name(0xC000), None);
+  }
+
+  #[test]
+  fn truncated_tail_gets_an_address_per_byte() {
+    let lines = Disassembler::new().disassemble(&[0x00, 0xC3, 0x50], 0x0100);
+    let lines: Vec<(u16, &str)> = lines.iter().map(|&(pc, ref s)| (pc, s.as_str())).collect();
+    assert_eq!(lines, vec![(0x0100, "NOP"), (0x0101, "DB 0xC3"), (0x0102, "DB 0x50")]);
+  }
+
+  #[test]
+  fn instr_stream_decodes_off_the_bus() {
+    // LD A,0x12; LDH (0x80),A; JP 0x0150; CB SWAP B
+    let mut bus = FlatBus::with_program(0x0150, &[0x3E, 0x12, 0xE0, 0x80, 0xC3, 0x50, 0x01,
+                                                  0xCB, 0x30]);
+    let dis = Disassembler::new();
+    let lines: Vec<(u16, String)> = InstrStream::new(&mut bus, 0x0150)
+      .take(4)
+      .map(|(pc, instr)| (pc, dis.format(&instr, pc)))
+      .collect();
+    let lines: Vec<(u16, &str)> = lines.iter().map(|&(pc, ref s)| (pc, s.as_str())).collect();
+
+    assert_eq!(lines, vec![(0x0150, "LD A,0x12"), (0x0152, "LDH (0xFF80),A"),
+                           (0x0154, "JP 0x0150"), (0x0157, "SWAP B")]);
+  }
+
+  #[test]
+  fn instr_stream_follows_the_mapped_bank() {
+    let mut bytes = rom("BANKS", 0x01, 0x01, 0x00);
+    bytes[0x8000] = 0x76;
+    fix_checksums(&mut bytes);
+    let mut mmu = MMU::new(Cartridge::new(bytes).unwrap());
+
+    let first = |mmu: &mut MMU| InstrStream::new(mmu, 0x4000).next().unwrap().1;
+    assert_eq!(first(&mut mmu).opcode(), Some(&Opcode::NOP));
+    mmu.write_u8(0x2000, 0x02);
+    assert_eq!(first(&mut mmu).opcode(), Some(&Opcode::HALT));
+  }
+}
@@ -0,0 +1,750 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use hw::bus::Bus;
+use hw::model::Model;
+
+use super::{ExecMode, Processor, Result, StepErr};
+use super::clock::{self, Clock, Frequency};
+use super::instr::{Cond, Immediate, Instr, Opcode, Operand, R16, R8};
+use super::instr::decode::DecodeErr;
+use super::register::{Flag, FlagRegister, Register};
+
+impl Processor {
+
+  /// Fetches, decodes and executes one instruction, advancing `clock` by the
+  /// T-cycles it took. On error PC is left pointing at the faulting instruction,
+  /// except for watchpoint hits, which are reported once the instruction completes.
+  pub fn step<B: Bus + ?Sized>(&mut self, bus: &mut B, clock: &mut Clock) -> Result<usize> {
+    if self.stopped {
+      clock.incr();
+      bus.tick(clock::CYCLE_INCREMENT);
+      return Ok(clock::CYCLE_INCREMENT);
+    }
+
+    let pc = self.regs.pc().get();
+    let instr = match fetch(bus, pc) {
+      Ok(instr) => instr,
+      Err(e) => {
+        bus.take_watch_hit();
+        return Err(e);
+      },
+    };
+    self.finish(&instr, pc, bus, clock)
+  }
+
+  /// Decodes one instruction from `bytes` and executes it as if it had been
+  /// fetched at PC, which moves past it. Meant for testing instructions in
+  /// isolation; the bus is only used for the instruction's own accesses and
+  /// ticks. Timing is that of single speed, and STOP never switches speed:
+  /// the DMG clock used here refuses, so the bus is left as it was.
+  pub fn execute_bytes<B: Bus + ?Sized>(&mut self, bytes: &[u8], bus: &mut B) -> Result<usize> {
+    let instr = try!(Instr::decode(bytes).map_err(StepErr::Decode));
+    let pc = self.regs.pc().get();
+    self.finish(&instr, pc, bus, &mut Clock::for_model(Model::DMG))
+  }
+
+  /// Executes `instr`, fetched from `pc`, and advances everything past it.
+  fn finish<B: Bus + ?Sized>(&mut self, instr: &Instr, pc: u16, bus: &mut B,
+                             clock: &mut Clock) -> Result<usize> {
+    self.regs.incr_pc(instr.len() as u16);
+
+    let cycles = match self.execute(instr, bus, clock) {
+      Ok(taken) => instr.cycles(taken),
+      // The failed instruction's watch hit would otherwise be reported by the
+      // next step.
+      Err(e) => {
+        self.regs.pc_mut().set(pc);
+        bus.take_watch_hit();
+        return Err(e);
+      },
+    };
+    match self.mode {
+      ExecMode::InstructionAtomic => {
+        clock.incr_n(cycles / clock::CYCLE_INCREMENT);
+        bus.tick(cycles);
+      },
+      // TODO interleave these with the instruction's own bus accesses; for now
+      // they all land after it.
+      ExecMode::TCycleStepped => {
+        for _ in 0 .. cycles / clock::CYCLE_INCREMENT {
+          clock.incr();
+          bus.tick(clock::CYCLE_INCREMENT);
+        }
+      },
+    }
+
+    if self.ime_pending > 0 {
+      self.ime_pending -= 1;
+      if self.ime_pending == 0 {
+        self.ime = true;
+      }
+    }
+
+    match bus.take_watch_hit() {
+      Some(hit) => Err(StepErr::Watchpoint(hit)),
+      None => Ok(cycles),
+    }
+  }
+
+  /// Executes `instr`, whose bytes PC has already moved past. Returns whether a
+  /// conditional branch was taken, which decides its timing; other
+  /// instructions return true.
+  fn execute<B: Bus + ?Sized>(&mut self, instr: &Instr, bus: &mut B, clock: &mut Clock) -> Result<bool> {
+    let (opcode, displace, immed) = match *instr {
+      Instr::Single { opcode, displace, immed, .. } => (opcode, displace, immed),
+      Instr::Illegal(op) => return Err(StepErr::Illegal(op)),
+    };
+
+    match opcode {
+      Opcode::NOP => {},
+      Opcode::LD(Operand::Reg8(r), Operand::Imm8) => self.set_reg8(r, imm8(immed)),
+      Opcode::INC(Operand::Reg8(r)) => {
+        let value = self.reg8(r);
+        let result = self.inc8(value);
+        self.set_reg8(r, result);
+      },
+      Opcode::DEC(Operand::Reg8(r)) => {
+        let value = self.reg8(r);
+        let result = self.dec8(value);
+        self.set_reg8(r, result);
+      },
+      Opcode::LD(Operand::Reg8(R8::A), src @ Operand::IndirectInc)
+      | Opcode::LD(Operand::Reg8(R8::A), src @ Operand::IndirectDec) => {
+        let value = self.read_hl(bus);
+        self.set_reg8(R8::A, value);
+        self.step_hl(src);
+      },
+      Opcode::LD(dst @ Operand::IndirectInc, Operand::Reg8(R8::A))
+      | Opcode::LD(dst @ Operand::IndirectDec, Operand::Reg8(R8::A)) => {
+        let a = self.reg8(R8::A);
+        self.write_hl(bus, a);
+        self.step_hl(dst);
+      },
+      // Read-modify-write: the byte is read, adjusted, then stored back.
+      Opcode::INC(Operand::Indirect(R16::HL)) => {
+        let value = self.read_hl(bus);
+        let result = self.inc8(value);
+        self.write_hl(bus, result);
+      },
+      Opcode::DEC(Operand::Indirect(R16::HL)) => {
+        let value = self.read_hl(bus);
+        let result = self.dec8(value);
+        self.write_hl(bus, result);
+      },
+      Opcode::SWAP(Operand::Reg8(r)) => {
+        let value = self.reg8(r);
+        let result = self.swap(value);
+        self.set_reg8(r, result);
+      },
+      Opcode::SWAP(Operand::Indirect(R16::HL)) => {
+        let value = self.read_hl(bus);
+        let result = self.swap(value);
+        self.write_hl(bus, result);
+      },
+      Opcode::LDH(Operand::HighImm8, Operand::Reg8(R8::A)) => {
+        let a = self.reg8(R8::A);
+        bus.write_u8(0xFF00 | imm8(immed) as u16, a);
+      },
+      Opcode::LDH(Operand::Reg8(R8::A), Operand::HighImm8) => {
+        let value = bus.read_u8(0xFF00 | imm8(immed) as u16);
+        self.set_reg8(R8::A, value);
+      },
+      Opcode::JP(cond, Operand::Imm16) => {
+        if !self.check(cond) {
+          return Ok(false);
+        }
+        self.regs.pc_mut().set(imm16(immed));
+      },
+      Opcode::CALL(cond) => {
+        if !self.check(cond) {
+          return Ok(false);
+        }
+        let ret = self.regs.pc().get();
+        try!(self.push_u16(bus, ret));
+        self.regs.pc_mut().set(imm16(immed));
+      },
+      Opcode::RET(cond) => {
+        if !self.check(cond) {
+          return Ok(false);
+        }
+        let ret = try!(self.pop_u16(bus));
+        self.regs.pc_mut().set(ret);
+      },
+      Opcode::PUSH(rr) => {
+        let value = self.reg16(rr);
+        try!(self.push_u16(bus, value));
+      },
+      Opcode::POP(rr) => {
+        let value = try!(self.pop_u16(bus));
+        self.set_reg16(rr, value);
+      },
+      // Unlike EI, RETI enables interrupts with no delay.
+      Opcode::RETI => {
+        let ret = try!(self.pop_u16(bus));
+        self.regs.pc_mut().set(ret);
+        self.ime = true;
+      },
+      Opcode::DI => {
+        self.ime = false;
+        self.ime_pending = 0;
+      },
+      // IME is set only after the instruction following EI, so `EI; DI` never
+      // enables interrupts. The count includes EI itself.
+      Opcode::EI => {
+        if !self.ime {
+          self.ime_pending = 2;
+        }
+      },
+      Opcode::JR(cond) => {
+        if !self.check(cond) {
+          return Ok(false);
+        }
+        // Adding the sign-extended offset wraps the same way a subtraction would.
+        let e = displace.unwrap_or(0);
+        self.regs.incr_pc(e as i16 as u16);
+      },
+      // A speed switch the clock refuses falls back to an ordinary STOP.
+      Opcode::STOP => {
+        let freq = match clock.frequency() {
+          Frequency::Single => Frequency::Double,
+          Frequency::Double => Frequency::Single,
+        };
+        if bus.speed_switch_armed() && clock.set_frequency(freq).is_ok() {
+          bus.switch_speed();
+        } else {
+          self.stopped = true;
+        }
+      },
+      _ => return Err(StepErr::Unimplemented(*instr)),
+    }
+    Ok(true)
+  }
+
+  /// Whether a branch on `cond` is taken; unconditional branches always are.
+  fn check(&self, cond: Option<Cond>) -> bool {
+    let f = self.regs.af().lower();
+    match cond {
+      None => true,
+      Some(Cond::NZ) => !f.is_set(Flag::Zero),
+      Some(Cond::Z) => f.is_set(Flag::Zero),
+      Some(Cond::NC) => !f.is_set(Flag::Carry),
+      Some(Cond::C) => f.is_set(Flag::Carry),
+    }
+  }
+
+  /// Reads the byte HL points to.
+  pub fn read_hl<B: Bus + ?Sized>(&self, bus: &mut B) -> u8 {
+    bus.read_u8(self.regs.hl().get())
+  }
+
+  /// Writes the byte HL points to.
+  pub fn write_hl<B: Bus + ?Sized>(&mut self, bus: &mut B, value: u8) {
+    bus.write_u8(self.regs.hl().get(), value)
+  }
+
+  /// Moves HL one byte on after a `(HL+)` or `(HL-)` access, wrapping at the
+  /// ends of the address space.
+  fn step_hl(&mut self, op: Operand) {
+    let hl = self.regs.hl().get();
+    let hl = match op {
+      Operand::IndirectInc => hl.wrapping_add(1),
+      _ => hl.wrapping_sub(1),
+    };
+    self.regs.hl_mut().set(hl);
+  }
+
+  /// 8-bit increment; sets Z, clears N, sets H on a carry out of bit 3. C is untouched.
+  fn inc8(&mut self, value: u8) -> u8 {
+    let result = value.wrapping_add(1);
+    self.set_flag(Flag::Zero, result == 0);
+    self.set_flag(Flag::AddSub, false);
+    self.set_flag(Flag::HalfCarry, value & 0x0F == 0x0F);
+    result
+  }
+
+  /// 8-bit decrement; sets Z, sets N, sets H on a borrow from bit 4. C is untouched.
+  fn dec8(&mut self, value: u8) -> u8 {
+    let result = value.wrapping_sub(1);
+    self.set_flag(Flag::Zero, result == 0);
+    self.set_flag(Flag::AddSub, true);
+    self.set_flag(Flag::HalfCarry, value & 0x0F == 0);
+    result
+  }
+
+  /// Exchanges the nibbles; sets Z and clears the rest. Unlike the shifts and
+  /// rotates, no bit moves into C.
+  fn swap(&mut self, value: u8) -> u8 {
+    let result = value.rotate_left(4);
+    self.set_flag(Flag::Zero, result == 0);
+    self.set_flag(Flag::AddSub, false);
+    self.set_flag(Flag::HalfCarry, false);
+    self.set_flag(Flag::Carry, false);
+    result
+  }
+
+  fn set_flag(&mut self, flag: Flag, on: bool) {
+    let f = self.regs.af_mut().lower_mut();
+    let mask = flag as u8;
+    let value = if on { f.get() | mask } else { f.get() & !mask };
+    f.set(value);
+  }
+
+  /// Pushes `value` high byte first, leaving SP at its low byte.
+  fn push_u16<B: Bus + ?Sized>(&mut self, bus: &mut B, value: u16) -> Result<()> {
+    let sp = self.regs.sp().get();
+    try!(self.check_stack([sp.wrapping_sub(1), sp.wrapping_sub(2)]));
+    self.regs.decr_sp(1);
+    bus.write_u8(self.regs.sp().get(), (value >> 8) as u8);
+    self.regs.decr_sp(1);
+    bus.write_u8(self.regs.sp().get(), value as u8);
+    Ok(())
+  }
+
+  fn pop_u16<B: Bus + ?Sized>(&mut self, bus: &mut B) -> Result<u16> {
+    let sp = self.regs.sp().get();
+    try!(self.check_stack([sp, sp.wrapping_add(1)]));
+    let lo = bus.read_u8(self.regs.sp().get());
+    self.regs.incr_sp(1);
+    let hi = bus.read_u8(self.regs.sp().get());
+    self.regs.incr_sp(1);
+    Ok((hi as u16) << 8 | lo as u16)
+  }
+
+  /// Fails if a stack guard is set and any of `addrs`, the bytes a push or
+  /// pop is about to touch, is outside it.
+  fn check_stack(&self, addrs: [u16; 2]) -> Result<()> {
+    let range = match self.stack_guard {
+      Some(ref range) => range,
+      None => return Ok(()),
+    };
+    match addrs.iter().find(|addr| !range.contains(addr)) {
+      Some(&addr) => Err(StepErr::StackGuard(addr)),
+      None => Ok(()),
+    }
+  }
+
+  fn reg8(&self, r: R8) -> u8 {
+    match r {
+      R8::A => self.regs.af().upper().get(),
+      R8::B => self.regs.bc().upper().get(),
+      R8::C => self.regs.bc().lower().get(),
+      R8::D => self.regs.de().upper().get(),
+      R8::E => self.regs.de().lower().get(),
+      R8::H => self.regs.hl().upper().get(),
+      R8::L => self.regs.hl().lower().get(),
+    }
+  }
+
+  fn reg16(&self, rr: R16) -> u16 {
+    match rr {
+      R16::AF => self.regs.af().get(),
+      R16::BC => self.regs.bc().get(),
+      R16::DE => self.regs.de().get(),
+      R16::HL => self.regs.hl().get(),
+      R16::SP => self.regs.sp().get(),
+    }
+  }
+
+  fn set_reg16(&mut self, rr: R16, value: u16) {
+    match rr {
+      R16::AF => self.regs.af_mut().set(value),
+      R16::BC => self.regs.bc_mut().set(value),
+      R16::DE => self.regs.de_mut().set(value),
+      R16::HL => self.regs.hl_mut().set(value),
+      R16::SP => self.regs.sp_mut().set(value),
+    }
+  }
+
+  fn set_reg8(&mut self, r: R8, value: u8) {
+    match r {
+      R8::A => self.regs.af_mut().upper_mut().set(value),
+      R8::B => self.regs.bc_mut().upper_mut().set(value),
+      R8::C => self.regs.bc_mut().lower_mut().set(value),
+      R8::D => self.regs.de_mut().upper_mut().set(value),
+      R8::E => self.regs.de_mut().lower_mut().set(value),
+      R8::H => self.regs.hl_mut().upper_mut().set(value),
+      R8::L => self.regs.hl_mut().lower_mut().set(value),
+    }
+  }
+
+}
+
+fn imm8(immed: Option<Immediate>) -> u8 {
+  match immed {
+    Some(Immediate::One(n)) => n,
+    _ => 0,
+  }
+}
+
+fn imm16(immed: Option<Immediate>) -> u16 {
+  match immed {
+    Some(Immediate::Two(nn)) => nn,
+    _ => 0,
+  }
+}
+
+/// Reads just enough bytes from `bus` to decode the instruction at `pc`.
+pub fn fetch<B: Bus + ?Sized>(bus: &mut B, pc: u16) -> Result<Instr> {
+  let mut bytes = [0u8; 3];
+  let mut len = 0;
+
+  loop {
+    match Instr::decode(&bytes[.. len]) {
+      Ok(instr) => return Ok(instr),
+      Err(DecodeErr::Truncated(needed)) => {
+        while len < needed {
+          bytes[len] = bus.read_u8(pc.wrapping_add(len as u16));
+          len += 1;
+        }
+      },
+      Err(e) => return Err(StepErr::Decode(e)),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hw::bus::tests::FlatBus;
+  use super::*;
+
+  /// A CPU about to run whatever is at 0x0100, with the stack at the top of memory.
+  fn cpu() -> Processor {
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    cpu.regs_mut().sp_mut().set(0xFFFE);
+    cpu
+  }
+
+  #[test]
+  fn call_then_ret_returns_past_the_call() {
+    let mut bus = FlatBus::with_program(0x0100, &[0xCD, 0x00, 0x02]);
+    bus.mem[0x0200] = 0xC9;
+    let mut cpu = cpu();
+    let mut clock = Clock::new(Frequency::Single);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 24);
+    assert_eq!(cpu.regs().pc().get(), 0x0200);
+    assert_eq!(cpu.regs().sp().get(), 0xFFFC);
+    assert_eq!(&bus.mem[0xFFFC .. 0xFFFE], &[0x03, 0x01]);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 16);
+    assert_eq!(cpu.regs().pc().get(), 0x0103);
+    assert_eq!(cpu.regs().sp().get(), 0xFFFE);
+  }
+
+  #[test]
+  fn reti_enables_interrupts_at_once() {
+    let mut bus = FlatBus::with_program(0x0100, &[0xD9]);
+    bus.mem[0xFFFC] = 0x34;
+    bus.mem[0xFFFD] = 0x12;
+    let mut cpu = cpu();
+    cpu.regs_mut().sp_mut().set(0xFFFC);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().pc().get(), 0x1234);
+    assert_eq!(cpu.regs().sp().get(), 0xFFFE);
+    assert!(cpu.ime());
+    assert!(!cpu.ime_pending());
+  }
+
+  #[test]
+  fn ei_takes_effect_after_the_next_instruction() {
+    let mut bus = FlatBus::with_program(0x0100, &[0xFB, 0x00]);
+    let mut cpu = cpu();
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(!cpu.ime());
+    assert!(cpu.ime_pending());
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(cpu.ime());
+    assert!(!cpu.ime_pending());
+  }
+
+  #[test]
+  fn di_right_after_ei_keeps_interrupts_off() {
+    let mut bus = FlatBus::with_program(0x0100, &[0xFB, 0xF3, 0x00]);
+    let mut cpu = cpu();
+    let mut clock = Clock::new(Frequency::Single);
+
+    for _ in 0..3 {
+      cpu.step(&mut bus, &mut clock).unwrap();
+      assert!(!cpu.ime());
+    }
+    assert!(!cpu.ime_pending());
+  }
+
+  #[test]
+  fn conditional_jr_charges_for_the_branch_taken() {
+    // JR Z,+2 with Z clear falls through; JR NZ,+2 then jumps.
+    let mut bus = FlatBus::with_program(0x0100, &[0x28, 0x02, 0x20, 0x02]);
+    let mut cpu = cpu();
+    cpu.regs_mut().af_mut().set(0x0000);
+    let mut clock = Clock::new(Frequency::Single);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 8);
+    assert_eq!(clock.time(), 8);
+    assert_eq!(cpu.regs().pc().get(), 0x0102);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 12);
+    assert_eq!(clock.time(), 20);
+    assert_eq!(cpu.regs().pc().get(), 0x0106);
+  }
+
+  #[test]
+  fn inc_and_dec_hl_modify_memory_and_flags() {
+    let mut bus = FlatBus::with_program(0x0100, &[0x34, 0x34, 0x35, 0x35]);
+    let mut cpu = cpu();
+    cpu.regs_mut().hl_mut().set(0xC000);
+    // Carry set beforehand, to show it's left alone.
+    cpu.regs_mut().af_mut().set(0x0010);
+    cpu.write_hl(&mut bus, 0x0F);
+    let mut clock = Clock::new(Frequency::Single);
+
+    // 0x0F + 1: half carry.
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 12);
+    assert_eq!(cpu.read_hl(&mut bus), 0x10);
+    assert_eq!(cpu.regs().af().lower().get(), 0x30);
+
+    // 0xFF + 1: zero and half carry.
+    cpu.write_hl(&mut bus, 0xFF);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.mem[0xC000], 0x00);
+    assert_eq!(cpu.regs().af().lower().get(), 0xB0);
+
+    // 0x00 - 1: subtract and half borrow.
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.mem[0xC000], 0xFF);
+    assert_eq!(cpu.regs().af().lower().get(), 0x70);
+
+    // 0xFF - 1: just subtract.
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.mem[0xC000], 0xFE);
+    assert_eq!(cpu.regs().af().lower().get(), 0x50);
+    assert_eq!(cpu.regs().hl().get(), 0xC000);
+  }
+
+  #[test]
+  fn hl_increment_and_decrement_loads() {
+    // LD (HL+),A; LD (HL-),A; LD A,(HL+); LD A,(HL-)
+    let mut bus = FlatBus::with_program(0x0100, &[0x22, 0x32, 0x2A, 0x3A]);
+    bus.mem[0xC000] = 0x11;
+    bus.mem[0xC001] = 0x22;
+    let mut cpu = cpu();
+    cpu.regs_mut().af_mut().set(0xAB00);
+    cpu.regs_mut().hl_mut().set(0xC002);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.mem[0xC002], 0xAB);
+    assert_eq!(cpu.regs().hl().get(), 0xC003);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.mem[0xC003], 0xAB);
+    assert_eq!(cpu.regs().hl().get(), 0xC002);
+
+    cpu.regs_mut().hl_mut().set(0xC000);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().af().upper().get(), 0x11);
+    assert_eq!(cpu.regs().hl().get(), 0xC001);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().af().upper().get(), 0x22);
+    assert_eq!(cpu.regs().hl().get(), 0xC000);
+  }
+
+  #[test]
+  fn hl_wraps_around_the_address_space() {
+    let mut bus = FlatBus::with_program(0x0100, &[0x22, 0x32]);
+    let mut cpu = cpu();
+    cpu.regs_mut().hl_mut().set(0xFFFF);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().hl().get(), 0x0000);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().hl().get(), 0xFFFF);
+  }
+
+  #[test]
+  fn stack_guard_stops_a_push_past_the_bottom() {
+    // CALL 0x0103; CALL 0x0106
+    let mut bus = FlatBus::with_program(0x0100, &[0xCD, 0x03, 0x01, 0xCD, 0x06, 0x01]);
+    let mut cpu = cpu();
+    cpu.regs_mut().sp_mut().set(0xC002);
+    cpu.set_stack_guard(0xC000 ..= 0xDFFF);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().sp().get(), 0xC000);
+
+    match cpu.step(&mut bus, &mut clock) {
+      Err(StepErr::StackGuard(0xBFFF)) => (),
+      x => panic!("expected StackGuard(0xBFFF), got {:?}", x),
+    }
+    assert_eq!(cpu.regs().sp().get(), 0xC000);
+    assert_eq!(&bus.mem[0xBFFE .. 0xC000], &[0x00, 0x00]);
+
+    // Without the guard the same push goes through.
+    cpu.clear_stack_guard();
+    cpu.regs_mut().pc_mut().set(0x0103);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().sp().get(), 0xBFFE);
+  }
+
+  #[test]
+  fn stack_guard_stops_a_pop_past_the_top() {
+    // RET
+    let mut bus = FlatBus::with_program(0x0100, &[0xC9]);
+    let mut cpu = cpu();
+    cpu.regs_mut().sp_mut().set(0xDFFF);
+    cpu.set_stack_guard(0xC000 ..= 0xDFFF);
+    let mut clock = Clock::new(Frequency::Single);
+
+    match cpu.step(&mut bus, &mut clock) {
+      Err(StepErr::StackGuard(0xE000)) => (),
+      x => panic!("expected StackGuard(0xE000), got {:?}", x),
+    }
+    assert_eq!(cpu.regs().sp().get(), 0xDFFF);
+  }
+
+  #[test]
+  fn stack_guard_allows_a_balanced_push_and_pop_at_the_top() {
+    // PUSH BC; POP BC
+    let mut bus = FlatBus::with_program(0x0100, &[0xC5, 0xC1]);
+    let mut cpu = cpu();
+    cpu.regs_mut().sp_mut().set(0xE000);
+    cpu.regs_mut().bc_mut().set(0x1234);
+    cpu.set_stack_guard(0xC000 ..= 0xDFFF);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(&bus.mem[0xDFFE .. 0xE000], &[0x34, 0x12]);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().sp().get(), 0xE000);
+    assert_eq!(cpu.regs().bc().get(), 0x1234);
+  }
+
+  #[test]
+  fn pop_af_clears_the_low_nibble_of_f() {
+    // POP AF; PUSH AF
+    let mut bus = FlatBus::with_program(0x0100, &[0xF1, 0xF5]);
+    bus.mem[0xFFFC] = 0xFF;
+    bus.mem[0xFFFD] = 0x12;
+    let mut cpu = cpu();
+    cpu.regs_mut().sp_mut().set(0xFFFC);
+    let mut clock = Clock::new(Frequency::Single);
+
+    assert_eq!(Instr::decode(&[0xF1]).unwrap().opcode(), Some(&Opcode::POP(R16::AF)));
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().af().get(), 0x12F0);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(&bus.mem[0xFFFC .. 0xFFFE], &[0xF0, 0x12]);
+  }
+
+  #[test]
+  fn atomic_mode_ticks_once_per_instruction() {
+    // CALL 0x0200; NOP
+    let mut bus = FlatBus::with_program(0x0100, &[0xCD, 0x00, 0x02, 0x00]);
+    let mut cpu = cpu();
+    assert_eq!(cpu.exec_mode(), ExecMode::InstructionAtomic);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(bus.ticks, vec![24, 4]);
+    assert_eq!(clock.time(), 28);
+  }
+
+  #[test]
+  fn stepped_mode_ticks_every_m_cycle() {
+    let mut bus = FlatBus::with_program(0x0100, &[0xCD, 0x00, 0x02]);
+    let mut cpu = cpu();
+    cpu.set_exec_mode(ExecMode::TCycleStepped);
+    let mut clock = Clock::new(Frequency::Single);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 24);
+    assert_eq!(bus.ticks, vec![4; 6]);
+    assert_eq!(clock.time(), 24);
+  }
+
+  #[test]
+  fn execute_bytes_runs_one_instruction() {
+    let mut bus = FlatBus::with_program(0x0000, &[]);
+    let mut cpu = cpu();
+
+    assert_eq!(cpu.execute_bytes(&[0x3E, 0x10], &mut bus).unwrap(), 8);
+    assert_eq!(cpu.regs().af().upper().get(), 0x10);
+
+    match cpu.execute_bytes(&[0x3E], &mut bus) {
+      Err(StepErr::Decode(DecodeErr::Truncated(2))) => (),
+      x => panic!("expected a truncated decode, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn swap_exchanges_nibbles_and_sets_only_z() {
+    // SWAP B; SWAP (HL); SWAP B
+    let mut bus = FlatBus::with_program(0x0100, &[0xCB, 0x30, 0xCB, 0x36, 0xCB, 0x30]);
+    bus.mem[0xC000] = 0x00;
+    let mut cpu = cpu();
+    cpu.regs_mut().bc_mut().set(0xF000);
+    cpu.regs_mut().hl_mut().set(0xC000);
+    cpu.regs_mut().af_mut().set(0x0070);
+    let mut clock = Clock::new(Frequency::Single);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 8);
+    assert_eq!(cpu.regs().bc().upper().get(), 0x0F);
+    assert_eq!(cpu.regs().af().lower().get(), 0x00);
+
+    assert_eq!(cpu.step(&mut bus, &mut clock).unwrap(), 16);
+    assert_eq!(bus.mem[0xC000], 0x00);
+    assert_eq!(cpu.regs().af().lower().get(), 0x80);
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert_eq!(cpu.regs().bc().upper().get(), 0xF0);
+    assert_eq!(cpu.regs().af().lower().get(), 0x00);
+  }
+
+  #[test]
+  fn swap_covers_every_operand() {
+    let mut bus = FlatBus::with_program(0x0000, &[]);
+    let mut cpu = cpu();
+    cpu.regs_mut().af_mut().set(0x9A00);
+    cpu.regs_mut().bc_mut().set(0x1234);
+    cpu.regs_mut().de_mut().set(0x5678);
+    // Swapping H and L leaves this address alone.
+    cpu.regs_mut().hl_mut().set(0x1122);
+    bus.mem[0x1122] = 0xA5;
+
+    for op in 0x30..0x38 {
+      match Instr::decode(&[0xCB, op]).unwrap().opcode() {
+        Some(&Opcode::SWAP(_)) => (),
+        x => panic!("expected SWAP for CB {:02X}, got {:?}", op, x),
+      }
+      cpu.execute_bytes(&[0xCB, op], &mut bus).unwrap();
+    }
+    assert_eq!(cpu.regs().af().upper().get(), 0xA9);
+    assert_eq!(cpu.regs().bc().get(), 0x2143);
+    assert_eq!(cpu.regs().de().get(), 0x6587);
+    assert_eq!(cpu.regs().hl().get(), 0x1122);
+    assert_eq!(bus.mem[0x1122], 0x5A);
+  }
+}
@@ -25,56 +25,632 @@ type safety where possible. use types to encode information about the instructio
 
 e.g. Instr::LD_RR(r1, r2)
 */
-use std::convert::{Into, TryFrom, TryInto};
-use std::result;
+use alloc::vec::Vec;
+use core::convert::{Into, TryFrom};
+use core::result;
 
-#[derive(PartialEq)]
-enum Prefix {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefix {
   CB = 0xCB,
-  DD = 0xDD,
-  ED = 0xED,
-  FD = 0xFD
 }
 
 const PREFIX_CB: u8 = Prefix::CB as u8;
-const PREFIX_DD: u8 = Prefix::DD as u8;
-const PREFIX_ED: u8 = Prefix::ED as u8;
-const PREFIX_FD: u8 = Prefix::FD as u8;
 
-enum Immediate {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Immediate {
   Zero,
   One(u8),
   Two(u16)
 }
 
-enum Opcode {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum R8 {
+  A,
+  B,
+  C,
+  D,
+  E,
+  H,
+  L
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum R16 {
+  AF,
+  BC,
+  DE,
+  HL,
+  SP
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Cond {
+  NZ,
+  Z,
+  NC,
+  C
+}
+
+/// Where an instruction reads from or writes to. Immediate and displacement
+/// values themselves live on the enclosing `Instr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+  Reg8(R8),
+  Reg16(R16),
+  /// `(BC)`, `(DE)` or `(HL)`.
+  Indirect(R16),
+  /// `(HL+)`: access through HL, then increment it.
+  IndirectInc,
+  /// `(HL-)`: access through HL, then decrement it.
+  IndirectDec,
+  /// `(C)`, i.e. `0xFF00 + C`.
+  HighC,
+  /// `n`
+  Imm8,
+  /// `nn`
+  Imm16,
+  /// `(nn)`
+  Absolute,
+  /// `(n)`, i.e. `0xFF00 + n`.
+  HighImm8,
+  /// `SP+e`
+  SPOffset,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Opcode {
+  NOP,
+  /// Always two bytes long; the second byte should be 0x00 and is kept as the
+  /// instruction's immediate so a corrupted STOP can be told apart.
+  STOP,
+  HALT,
+  DI,
+  EI,
+  LD(Operand, Operand),
+  LDH(Operand, Operand),
+  PUSH(R16),
+  POP(R16),
+  ADD(Operand, Operand),
+  ADC(Operand),
+  SUB(Operand),
+  SBC(Operand),
+  AND(Operand),
+  XOR(Operand),
+  OR(Operand),
+  CP(Operand),
+  INC(Operand),
+  DEC(Operand),
+  DAA,
+  CPL,
+  SCF,
+  CCF,
+  RLCA,
+  RRCA,
+  RLA,
+  RRA,
+  JR(Option<Cond>),
+  JP(Option<Cond>, Operand),
+  CALL(Option<Cond>),
+  RET(Option<Cond>),
+  RETI,
+  RST(u8),
+  RLC(Operand),
+  RRC(Operand),
+  RL(Operand),
+  RR(Operand),
+  SLA(Operand),
+  SRA(Operand),
+  SWAP(Operand),
+  SRL(Operand),
+  BIT(u8, Operand),
+  RES(u8, Operand),
+  SET(u8, Operand),
+}
+
+impl Opcode {
+  /// See `Instr::cycles`.
+  pub fn cycles(&self, branch_taken: bool) -> usize {
+    let branch = |cond: Option<Cond>, taken: usize, skipped: usize| {
+      if cond.is_none() || branch_taken { taken } else { skipped }
+    };
+
+    match *self {
+      Opcode::NOP | Opcode::STOP | Opcode::HALT | Opcode::DI | Opcode::EI
+      | Opcode::DAA | Opcode::CPL | Opcode::SCF | Opcode::CCF
+      | Opcode::RLCA | Opcode::RRCA | Opcode::RLA | Opcode::RRA => 4,
+
+      Opcode::LD(Operand::Reg16(R16::SP), Operand::Reg16(R16::HL)) => 8,
+      Opcode::LD(Operand::Reg16(_), Operand::Imm16) => 12,
+      Opcode::LD(Operand::Reg16(R16::HL), Operand::SPOffset) => 12,
+      Opcode::LD(Operand::Absolute, Operand::Reg16(R16::SP)) => 20,
+      Opcode::LD(Operand::Absolute, _) | Opcode::LD(_, Operand::Absolute) => 16,
+      Opcode::LD(dst, src) => 4 + access_cycles(dst) + access_cycles(src),
+      Opcode::LDH(_, _) => 12,
+      Opcode::PUSH(_) => 16,
+      Opcode::POP(_) => 12,
+
+      Opcode::ADD(Operand::Reg16(R16::HL), _) => 8,
+      Opcode::ADD(Operand::Reg16(R16::SP), _) => 16,
+      Opcode::ADD(_, src) | Opcode::ADC(src) | Opcode::SUB(src) | Opcode::SBC(src)
+      | Opcode::AND(src) | Opcode::XOR(src) | Opcode::OR(src) | Opcode::CP(src) => {
+        4 + access_cycles(src)
+      },
+      Opcode::INC(Operand::Reg16(_)) | Opcode::DEC(Operand::Reg16(_)) => 8,
+      // Read-modify-write: memory operands pay for both the load and the store.
+      Opcode::INC(op) | Opcode::DEC(op) => 4 + 2 * access_cycles(op),
+
+      Opcode::JR(cond) => branch(cond, 12, 8),
+      Opcode::JP(_, Operand::Reg16(R16::HL)) => 4,
+      Opcode::JP(cond, _) => branch(cond, 16, 12),
+      Opcode::CALL(cond) => branch(cond, 24, 12),
+      Opcode::RET(None) | Opcode::RETI => 16,
+      Opcode::RET(cond) => branch(cond, 20, 8),
+      Opcode::RST(_) => 16,
+
+      Opcode::BIT(_, op) => 8 + access_cycles(op),
+      Opcode::RLC(op) | Opcode::RRC(op) | Opcode::RL(op) | Opcode::RR(op)
+      | Opcode::SLA(op) | Opcode::SRA(op) | Opcode::SWAP(op) | Opcode::SRL(op)
+      | Opcode::RES(_, op) | Opcode::SET(_, op) => 8 + 2 * access_cycles(op),
+    }
+  }
+}
+
+impl Opcode {
+  /// The instruction's name without operands, e.g. `"LD"`.
+  pub fn mnemonic(&self) -> &'static str {
+    match *self {
+      Opcode::NOP => "NOP",
+      Opcode::STOP => "STOP",
+      Opcode::HALT => "HALT",
+      Opcode::DI => "DI",
+      Opcode::EI => "EI",
+      Opcode::LD(..) => "LD",
+      Opcode::LDH(..) => "LDH",
+      Opcode::PUSH(..) => "PUSH",
+      Opcode::POP(..) => "POP",
+      Opcode::ADD(..) => "ADD",
+      Opcode::ADC(..) => "ADC",
+      Opcode::SUB(..) => "SUB",
+      Opcode::SBC(..) => "SBC",
+      Opcode::AND(..) => "AND",
+      Opcode::XOR(..) => "XOR",
+      Opcode::OR(..) => "OR",
+      Opcode::CP(..) => "CP",
+      Opcode::INC(..) => "INC",
+      Opcode::DEC(..) => "DEC",
+      Opcode::DAA => "DAA",
+      Opcode::CPL => "CPL",
+      Opcode::SCF => "SCF",
+      Opcode::CCF => "CCF",
+      Opcode::RLCA => "RLCA",
+      Opcode::RRCA => "RRCA",
+      Opcode::RLA => "RLA",
+      Opcode::RRA => "RRA",
+      Opcode::JR(..) => "JR",
+      Opcode::JP(..) => "JP",
+      Opcode::CALL(..) => "CALL",
+      Opcode::RET(..) => "RET",
+      Opcode::RETI => "RETI",
+      Opcode::RST(..) => "RST",
+      Opcode::RLC(..) => "RLC",
+      Opcode::RRC(..) => "RRC",
+      Opcode::RL(..) => "RL",
+      Opcode::RR(..) => "RR",
+      Opcode::SLA(..) => "SLA",
+      Opcode::SRA(..) => "SRA",
+      Opcode::SWAP(..) => "SWAP",
+      Opcode::SRL(..) => "SRL",
+      Opcode::BIT(..) => "BIT",
+      Opcode::RES(..) => "RES",
+      Opcode::SET(..) => "SET",
+    }
+  }
+
+  pub fn flag_effects(&self) -> FlagEffects {
+    use self::FlagEffect::{Calculated as X, Clear as O, Set as I, Unchanged as U};
+    let fx = |zero, add_sub, half_carry, carry| FlagEffects { zero, add_sub, half_carry, carry };
+
+    match *self {
+      // Everything is restored from the stack.
+      Opcode::POP(R16::AF) => fx(X, X, X, X),
+      Opcode::LD(_, Operand::SPOffset) | Opcode::ADD(Operand::Reg16(R16::SP), _) => fx(O, O, X, X),
+      Opcode::ADD(Operand::Reg16(R16::HL), _) => fx(U, O, X, X),
+      Opcode::ADD(_, _) | Opcode::ADC(_) => fx(X, O, X, X),
+      Opcode::SUB(_) | Opcode::SBC(_) | Opcode::CP(_) => fx(X, I, X, X),
+      Opcode::AND(_) => fx(X, O, I, O),
+      Opcode::XOR(_) | Opcode::OR(_) => fx(X, O, O, O),
+      Opcode::INC(Operand::Reg16(_)) | Opcode::DEC(Operand::Reg16(_)) => fx(U, U, U, U),
+      Opcode::INC(_) => fx(X, O, X, U),
+      Opcode::DEC(_) => fx(X, I, X, U),
+      Opcode::DAA => fx(X, U, O, X),
+      Opcode::CPL => fx(U, I, I, U),
+      Opcode::SCF => fx(U, O, O, I),
+      Opcode::CCF => fx(U, O, O, X),
+      // Unlike their CB-prefixed forms, these always clear Z.
+      Opcode::RLCA | Opcode::RRCA | Opcode::RLA | Opcode::RRA => fx(O, O, O, X),
+      Opcode::SWAP(_) => fx(X, O, O, O),
+      Opcode::RLC(_) | Opcode::RRC(_) | Opcode::RL(_) | Opcode::RR(_)
+      | Opcode::SLA(_) | Opcode::SRA(_) | Opcode::SRL(_) => fx(X, O, O, X),
+      Opcode::BIT(_, _) => fx(X, O, I, U),
+      _ => fx(U, U, U, U),
+    }
+  }
+}
+
+impl Opcode {
+  /// See `Instr::effects`.
+  pub fn effects(&self) -> InstrEffects {
+    let a = Operand::Reg8(R8::A);
+    let mut fx = InstrEffects::default();
+
+    match *self {
+      Opcode::NOP | Opcode::STOP | Opcode::HALT | Opcode::SCF | Opcode::CCF => {},
+      Opcode::DI | Opcode::EI => fx.write(Location::IME),
+      Opcode::LD(dst, src) | Opcode::LDH(dst, src) => {
+        fx.read_operand(src);
+        fx.write_operand(dst);
+      },
+      Opcode::PUSH(rr) => {
+        fx.read_reg16(rr);
+        fx.read(Location::SP);
+        fx.write(Location::SP);
+        fx.write(Location::Memory);
+      },
+      Opcode::POP(rr) => {
+        fx.read(Location::SP);
+        fx.read(Location::Memory);
+        fx.write(Location::SP);
+        fx.write_reg16(rr);
+      },
+      Opcode::ADD(dst, src) => {
+        fx.read_operand(dst);
+        fx.read_operand(src);
+        fx.write_operand(dst);
+      },
+      Opcode::ADC(src) | Opcode::SUB(src) | Opcode::SBC(src)
+      | Opcode::AND(src) | Opcode::XOR(src) | Opcode::OR(src) => {
+        fx.read_operand(a);
+        fx.read_operand(src);
+        fx.write_operand(a);
+      },
+      Opcode::CP(src) => {
+        fx.read_operand(a);
+        fx.read_operand(src);
+      },
+      Opcode::DAA | Opcode::CPL | Opcode::RLCA | Opcode::RRCA | Opcode::RLA | Opcode::RRA => {
+        fx.read_operand(a);
+        fx.write_operand(a);
+      },
+      Opcode::INC(op) | Opcode::DEC(op)
+      | Opcode::RLC(op) | Opcode::RRC(op) | Opcode::RL(op) | Opcode::RR(op)
+      | Opcode::SLA(op) | Opcode::SRA(op) | Opcode::SWAP(op) | Opcode::SRL(op)
+      | Opcode::RES(_, op) | Opcode::SET(_, op) => {
+        fx.read_operand(op);
+        fx.write_operand(op);
+      },
+      Opcode::BIT(_, op) => fx.read_operand(op),
+      Opcode::JR(_) => {
+        fx.read(Location::PC);
+        fx.write(Location::PC);
+      },
+      Opcode::JP(_, op) => {
+        fx.read_operand(op);
+        fx.write(Location::PC);
+      },
+      Opcode::CALL(_) | Opcode::RST(_) => {
+        fx.read(Location::PC);
+        fx.read(Location::SP);
+        fx.write(Location::SP);
+        fx.write(Location::Memory);
+        fx.write(Location::PC);
+      },
+      Opcode::RET(_) | Opcode::RETI => {
+        fx.read(Location::SP);
+        fx.read(Location::Memory);
+        fx.write(Location::SP);
+        fx.write(Location::PC);
+        if *self == Opcode::RETI {
+          fx.write(Location::IME);
+        }
+      },
+    }
+
+    // Flags read as inputs: branch conditions, carry-in, and DAA's adjustment.
+    match *self {
+      Opcode::JR(Some(cond)) | Opcode::JP(Some(cond), _)
+      | Opcode::CALL(Some(cond)) | Opcode::RET(Some(cond)) => match cond {
+        Cond::NZ | Cond::Z => fx.read(Location::Zero),
+        Cond::NC | Cond::C => fx.read(Location::Carry),
+      },
+      Opcode::ADC(_) | Opcode::SBC(_) | Opcode::RLA | Opcode::RRA
+      | Opcode::RL(_) | Opcode::RR(_) | Opcode::CCF => fx.read(Location::Carry),
+      Opcode::DAA => {
+        fx.read(Location::AddSub);
+        fx.read(Location::HalfCarry);
+        fx.read(Location::Carry);
+      },
+      _ => {},
+    }
+
+    let flags = self.flag_effects();
+    let written = [
+      (flags.zero, Location::Zero),
+      (flags.add_sub, Location::AddSub),
+      (flags.half_carry, Location::HalfCarry),
+      (flags.carry, Location::Carry),
+    ];
+    for &(effect, loc) in written.iter() {
+      if effect != FlagEffect::Unchanged {
+        fx.write(loc);
+      }
+    }
+    fx
+  }
+}
+
+/// Extra T-cycles an operand adds over a plain register: one memory access, or
+/// one extra fetch for an immediate byte.
+fn access_cycles(op: Operand) -> usize {
+  match op {
+    Operand::Indirect(_) | Operand::IndirectInc | Operand::IndirectDec
+    | Operand::HighC | Operand::Imm8 => 4,
+    _ => 0,
+  }
+}
 
+/// How an instruction leaves one flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlagEffect {
+  Set,
+  Clear,
+  /// Depends on the operands.
+  Calculated,
+  Unchanged,
 }
 
-enum Instr {
+/// The effect on each flag of F, as in the usual `Z N H C` notation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlagEffects {
+  pub zero: FlagEffect,
+  pub add_sub: FlagEffect,
+  pub half_carry: FlagEffect,
+  pub carry: FlagEffect,
+}
+
+/// Something an instruction can read or write. 16-bit registers appear as
+/// their halves, and F as its four flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+  Reg(R8),
+  SP,
+  PC,
+  Zero,
+  AddSub,
+  HalfCarry,
+  Carry,
+  /// Any byte on the bus.
+  Memory,
+  /// The interrupt master enable.
+  IME,
+}
+
+/// What an instruction reads and writes, each location listed once. Reading
+/// the instruction's own bytes isn't counted, and a conditional branch lists
+/// everything it would touch if taken.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InstrEffects {
+  pub reads: Vec<Location>,
+  pub writes: Vec<Location>,
+}
+
+impl InstrEffects {
+  fn read(&mut self, loc: Location) {
+    if !self.reads.contains(&loc) {
+      self.reads.push(loc);
+    }
+  }
+
+  fn write(&mut self, loc: Location) {
+    if !self.writes.contains(&loc) {
+      self.writes.push(loc);
+    }
+  }
+
+  fn read_reg16(&mut self, rr: R16) {
+    for &loc in reg16_locations(rr).iter() {
+      self.read(loc);
+    }
+  }
+
+  fn write_reg16(&mut self, rr: R16) {
+    for &loc in reg16_locations(rr).iter() {
+      self.write(loc);
+    }
+  }
+
+  /// Whatever it takes to work out the address of a memory operand.
+  fn read_address(&mut self, op: Operand) {
+    match op {
+      Operand::Indirect(rr) => self.read_reg16(rr),
+      Operand::IndirectInc | Operand::IndirectDec => self.read_reg16(R16::HL),
+      Operand::HighC => self.read(Location::Reg(R8::C)),
+      _ => {},
+    }
+  }
+
+  fn read_operand(&mut self, op: Operand) {
+    self.read_address(op);
+    match op {
+      Operand::Reg8(r) => self.read(Location::Reg(r)),
+      Operand::Reg16(rr) => self.read_reg16(rr),
+      Operand::SPOffset => self.read(Location::SP),
+      Operand::Imm8 | Operand::Imm16 => {},
+      _ => self.read(Location::Memory),
+    }
+    self.step_hl(op);
+  }
+
+  fn write_operand(&mut self, op: Operand) {
+    self.read_address(op);
+    match op {
+      Operand::Reg8(r) => self.write(Location::Reg(r)),
+      Operand::Reg16(rr) => self.write_reg16(rr),
+      Operand::SPOffset | Operand::Imm8 | Operand::Imm16 => {},
+      _ => self.write(Location::Memory),
+    }
+    self.step_hl(op);
+  }
+
+  /// `(HL+)` and `(HL-)` also write HL.
+  fn step_hl(&mut self, op: Operand) {
+    if op == Operand::IndirectInc || op == Operand::IndirectDec {
+      self.write_reg16(R16::HL);
+    }
+  }
+}
+
+fn reg16_locations(rr: R16) -> Vec<Location> {
+  match rr {
+    R16::AF => vec![Location::Reg(R8::A), Location::Zero, Location::AddSub,
+                    Location::HalfCarry, Location::Carry],
+    R16::BC => vec![Location::Reg(R8::B), Location::Reg(R8::C)],
+    R16::DE => vec![Location::Reg(R8::D), Location::Reg(R8::E)],
+    R16::HL => vec![Location::Reg(R8::H), Location::Reg(R8::L)],
+    R16::SP => vec![Location::SP],
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instr {
   Single {
     prefix: Option<Prefix>,
     opcode: Opcode,
     displace: Option<i8>,
     immed: Option<Immediate>
   },
-  SpecialDD {
-    displace: i8,
-    opcode: Opcode
-  },
-  SpecialFD {
-    displace: i8,
-    opcode: Opcode
-  }
+  /// One of the opcodes the LR35902 leaves undefined; executing it locks up the CPU.
+  Illegal(u8),
 }
 
-type Result<T> = result::Result<T, decode::DecodeErr>;
+pub type Result<T> = result::Result<T, decode::DecodeErr>;
 
 impl Instr {
 
+  /// Decodes the instruction at the start of `raw`. Trailing bytes are ignored.
   pub fn decode(raw: &[u8]) -> Result<Instr> {
-    // inspect the first byte
-    unimplemented!()
+    let first = match raw.first() {
+      Some(&b) => b,
+      None => return Err(decode::DecodeErr::Truncated(1)),
+    };
+
+    let (prefix, opcode) = if first == PREFIX_CB {
+      match raw.get(1) {
+        Some(&b) => (Some(Prefix::CB), decode::prefixed(b)),
+        None => return Err(decode::DecodeErr::Truncated(2)),
+      }
+    } else {
+      match decode::unprefixed(first) {
+        Some(op) => (None, op),
+        None => return Ok(Instr::Illegal(first)),
+      }
+    };
+
+    let start = if prefix.is_some() { 2 } else { 1 };
+    let operand = |n: usize| -> Result<&[u8]> {
+      raw.get(start .. start + n).ok_or(decode::DecodeErr::Truncated(start + n))
+    };
+
+    let (displace, immed) = match decode::trailing(&opcode) {
+      decode::Trailing::None => (None, None),
+      decode::Trailing::Displace => (Some(operand(1)?[0] as i8), None),
+      decode::Trailing::One => (None, Some(Immediate::One(operand(1)?[0]))),
+      decode::Trailing::Two => {
+        let b = operand(2)?;
+        (None, Some(Immediate::Two(b[0] as u16 | (b[1] as u16) << 8)))
+      },
+    };
+
+    Ok(Instr::Single { prefix, opcode, displace, immed })
+  }
+
+  /// The bytes `decode` would read this instruction from: prefix, opcode, then
+  /// any displacement or little-endian immediate. Empty if the opcode has no
+  /// encoding, which only happens for hand-built instructions.
+  pub fn encode(&self) -> Vec<u8> {
+    let (prefix, opcode, displace, immed) = match *self {
+      Instr::Single { prefix, ref opcode, displace, immed } => (prefix, opcode, displace, immed),
+      Instr::Illegal(op) => return vec![op],
+    };
+
+    let op = match decode::encode(prefix, opcode) {
+      Some(op) => op,
+      None => return Vec::new(),
+    };
+
+    let mut bytes = Vec::with_capacity(self.len());
+    if let Some(prefix) = prefix {
+      bytes.push(prefix.into());
+    }
+    bytes.push(op);
+    if let Some(d) = displace {
+      bytes.push(d as u8);
+    }
+    match immed {
+      None | Some(Immediate::Zero) => {},
+      Some(Immediate::One(n)) => bytes.push(n),
+      Some(Immediate::Two(nn)) => bytes.extend_from_slice(&[nn as u8, (nn >> 8) as u8]),
+    }
+    bytes
+  }
+
+  /// The opcodes the LR35902 leaves undefined, which decode to `Illegal`.
+  /// Finding one in code is a sign of corruption or a hack.
+  pub fn illegal_opcodes() -> &'static [u8] {
+    &decode::ILLEGAL_OPCODES
+  }
+
+  /// Encoded length in bytes, including any prefix and trailing operand bytes.
+  /// Never zero, so there's no `is_empty`.
+  #[allow(clippy::len_without_is_empty)]
+  pub fn len(&self) -> usize {
+    match *self {
+      Instr::Single { prefix, displace, immed, .. } => {
+        let prefix = if prefix.is_some() { 1 } else { 0 };
+        let displace = if displace.is_some() { 1 } else { 0 };
+        let immed = match immed {
+          None | Some(Immediate::Zero) => 0,
+          Some(Immediate::One(_)) => 1,
+          Some(Immediate::Two(_)) => 2,
+        };
+        prefix + 1 + displace + immed
+      },
+      Instr::Illegal(_) => 1,
+    }
+  }
+
+  pub fn opcode(&self) -> Option<&Opcode> {
+    match *self {
+      Instr::Single { ref opcode, .. } => Some(opcode),
+      Instr::Illegal(_) => None,
+    }
+  }
+
+  /// Registers, flags and memory the instruction reads and writes, worked out
+  /// from the opcode alone. Illegal opcodes touch nothing.
+  pub fn effects(&self) -> InstrEffects {
+    match *self {
+      Instr::Single { ref opcode, .. } => opcode.effects(),
+      Instr::Illegal(_) => InstrEffects::default(),
+    }
+  }
+
+  /// T-cycles taken to execute. `branch_taken` selects between the two timings
+  /// of a conditional jump, call or return and is ignored otherwise.
+  pub fn cycles(&self, branch_taken: bool) -> usize {
+    match *self {
+      Instr::Single { ref opcode, .. } => opcode.cycles(branch_taken),
+      Instr::Illegal(_) => 4,
+    }
   }
 
 }
@@ -83,9 +659,6 @@ impl Into<u8> for Prefix {
   fn into(self) -> u8 {
     match self {
       Prefix::CB => PREFIX_CB,
-      Prefix::DD => PREFIX_DD,
-      Prefix::ED => PREFIX_ED,
-      Prefix::FD => PREFIX_FD
     }
   }
 }
@@ -95,74 +668,386 @@ impl TryFrom<u8> for Prefix {
   fn try_from(raw: u8) -> result::Result<Self, Self::Error> {
     match raw {
       PREFIX_CB => Ok(Prefix::CB),
-      PREFIX_DD => Ok(Prefix::DD),
-      PREFIX_ED => Ok(Prefix::ED),
-      PREFIX_FD => Ok(Prefix::FD),
       _ => Err(decode::DecodeErr::UnknownPrefix(raw))
     }
   }
 }
 
-mod decode {
-  use std::result;
+pub mod decode {
+  use core::result;
+
+  use super::{Cond, Opcode, Operand, Prefix, R8, R16};
 
   pub type Result<T> = result::Result<T, DecodeErr>;
 
-  /// Marker trait for types eligible to be used as Decoder states.
-  pub trait DecoderState {}
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  pub enum DecodeErr {
+    UnknownPrefix(u8),
+    /// The input ended early; holds the length the instruction needed.
+    Truncated(usize),
+  }
 
-  pub struct Decoder<S: DecoderState> {
-    bytes: [u8; 4],
-    state: S,
+  /// Bytes following the opcode.
+  pub enum Trailing {
+    None,
+    Displace,
+    One,
+    Two,
   }
 
-  pub enum DecodeErr {
-    UnknownPrefix(u8),
+  // The tables below follow the usual x/y/z split of the opcode byte:
+  // x = bits 7-6, y = bits 5-3, z = bits 2-0, p = y >> 1, q = y & 1.
+
+  fn r(i: u8) -> Operand {
+    match i {
+      0 => Operand::Reg8(R8::B),
+      1 => Operand::Reg8(R8::C),
+      2 => Operand::Reg8(R8::D),
+      3 => Operand::Reg8(R8::E),
+      4 => Operand::Reg8(R8::H),
+      5 => Operand::Reg8(R8::L),
+      6 => Operand::Indirect(R16::HL),
+      _ => Operand::Reg8(R8::A),
+    }
   }
 
-  struct Start {
+  fn rp(i: u8) -> R16 {
+    match i {
+      0 => R16::BC,
+      1 => R16::DE,
+      2 => R16::HL,
+      _ => R16::SP,
+    }
+  }
 
+  fn rp2(i: u8) -> R16 {
+    match i {
+      0 => R16::BC,
+      1 => R16::DE,
+      2 => R16::HL,
+      _ => R16::AF,
+    }
   }
 
-  struct Prefix {
+  fn cc(i: u8) -> Cond {
+    match i {
+      0 => Cond::NZ,
+      1 => Cond::Z,
+      2 => Cond::NC,
+      _ => Cond::C,
+    }
+  }
 
+  fn alu(i: u8, src: Operand) -> Opcode {
+    match i {
+      0 => Opcode::ADD(Operand::Reg8(R8::A), src),
+      1 => Opcode::ADC(src),
+      2 => Opcode::SUB(src),
+      3 => Opcode::SBC(src),
+      4 => Opcode::AND(src),
+      5 => Opcode::XOR(src),
+      6 => Opcode::OR(src),
+      _ => Opcode::CP(src),
+    }
   }
 
-  struct DblPrefix {
+  /// The unprefixed opcodes the LR35902 leaves undefined. Every other byte,
+  /// prefixed or not, decodes to an instruction.
+  pub const ILLEGAL_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+  ];
+
+  /// Decodes an opcode byte that is not behind the 0xCB prefix. Returns `None`
+  /// for `ILLEGAL_OPCODES`.
+  pub fn unprefixed(op: u8) -> Option<Opcode> {
+    if ILLEGAL_OPCODES.contains(&op) {
+      return None;
+    }
+
+    let (x, y, z) = (op >> 6, (op >> 3) & 0x7, op & 0x7);
+    let (p, q) = (y >> 1, y & 0x1);
+    let a = Operand::Reg8(R8::A);
+
+    let opcode = match (x, z) {
+      (0, 0) => match y {
+        0 => Opcode::NOP,
+        1 => Opcode::LD(Operand::Absolute, Operand::Reg16(R16::SP)),
+        2 => Opcode::STOP,
+        3 => Opcode::JR(None),
+        _ => Opcode::JR(Some(cc(y - 4))),
+      },
+      (0, 1) if q == 0 => Opcode::LD(Operand::Reg16(rp(p)), Operand::Imm16),
+      (0, 1) => Opcode::ADD(Operand::Reg16(R16::HL), Operand::Reg16(rp(p))),
+      (0, 2) => {
+        let mem = match p {
+          0 => Operand::Indirect(R16::BC),
+          1 => Operand::Indirect(R16::DE),
+          2 => Operand::IndirectInc,
+          _ => Operand::IndirectDec,
+        };
+        if q == 0 { Opcode::LD(mem, a) } else { Opcode::LD(a, mem) }
+      },
+      (0, 3) if q == 0 => Opcode::INC(Operand::Reg16(rp(p))),
+      (0, 3) => Opcode::DEC(Operand::Reg16(rp(p))),
+      (0, 4) => Opcode::INC(r(y)),
+      (0, 5) => Opcode::DEC(r(y)),
+      (0, 6) => Opcode::LD(r(y), Operand::Imm8),
+      (0, _) => match y {
+        0 => Opcode::RLCA,
+        1 => Opcode::RRCA,
+        2 => Opcode::RLA,
+        3 => Opcode::RRA,
+        4 => Opcode::DAA,
+        5 => Opcode::CPL,
+        6 => Opcode::SCF,
+        _ => Opcode::CCF,
+      },
+      (1, 6) if y == 6 => Opcode::HALT,
+      (1, _) => Opcode::LD(r(y), r(z)),
+      (2, _) => alu(y, r(z)),
+      (_, 0) => match y {
+        0 ..= 3 => Opcode::RET(Some(cc(y))),
+        4 => Opcode::LDH(Operand::HighImm8, a),
+        5 => Opcode::ADD(Operand::Reg16(R16::SP), Operand::Imm8),
+        6 => Opcode::LDH(a, Operand::HighImm8),
+        _ => Opcode::LD(Operand::Reg16(R16::HL), Operand::SPOffset),
+      },
+      (_, 1) if q == 0 => Opcode::POP(rp2(p)),
+      (_, 1) => match p {
+        0 => Opcode::RET(None),
+        1 => Opcode::RETI,
+        2 => Opcode::JP(None, Operand::Reg16(R16::HL)),
+        _ => Opcode::LD(Operand::Reg16(R16::SP), Operand::Reg16(R16::HL)),
+      },
+      (_, 2) => match y {
+        0 ..= 3 => Opcode::JP(Some(cc(y)), Operand::Imm16),
+        4 => Opcode::LD(Operand::HighC, a),
+        5 => Opcode::LD(Operand::Absolute, a),
+        6 => Opcode::LD(a, Operand::HighC),
+        _ => Opcode::LD(a, Operand::Absolute),
+      },
+      (_, 3) => match y {
+        0 => Opcode::JP(None, Operand::Imm16),
+        6 => Opcode::DI,
+        7 => Opcode::EI,
+        _ => return None,
+      },
+      (_, 4) => match y {
+        0 ..= 3 => Opcode::CALL(Some(cc(y))),
+        _ => return None,
+      },
+      (_, 5) if q == 0 => Opcode::PUSH(rp2(p)),
+      (_, 5) => match p {
+        0 => Opcode::CALL(None),
+        _ => return None,
+      },
+      (_, 6) => alu(y, Operand::Imm8),
+      (_, _) => Opcode::RST(y * 8),
+    };
 
+    Some(opcode)
   }
 
-  struct Opcode {
+  /// Decodes the byte following a 0xCB prefix. Every value is defined.
+  pub fn prefixed(op: u8) -> Opcode {
+    let (x, y, z) = (op >> 6, (op >> 3) & 0x7, op & 0x7);
 
+    match x {
+      0 => match y {
+        0 => Opcode::RLC(r(z)),
+        1 => Opcode::RRC(r(z)),
+        2 => Opcode::RL(r(z)),
+        3 => Opcode::RR(r(z)),
+        4 => Opcode::SLA(r(z)),
+        5 => Opcode::SRA(r(z)),
+        6 => Opcode::SWAP(r(z)),
+        _ => Opcode::SRL(r(z)),
+      },
+      1 => Opcode::BIT(y, r(z)),
+      2 => Opcode::RES(y, r(z)),
+      _ => Opcode::SET(y, r(z)),
+    }
   }
 
-  struct Displace {
+  /// The opcode byte that decodes to `opcode` behind `prefix`. The tables are
+  /// small enough to search, which keeps the decoder the only place the
+  /// encoding is spelled out.
+  pub fn encode(prefix: Option<Prefix>, opcode: &Opcode) -> Option<u8> {
+    (0 ..= 0xFF).find(|&op| match prefix {
+      Some(Prefix::CB) => prefixed(op) == *opcode,
+      None => unprefixed(op).as_ref() == Some(opcode),
+    })
+  }
 
+  pub fn trailing(opcode: &Opcode) -> Trailing {
+    match *opcode {
+      Opcode::STOP => Trailing::One,
+      Opcode::JR(_) => Trailing::Displace,
+      Opcode::ADD(Operand::Reg16(R16::SP), _) => Trailing::Displace,
+      Opcode::LD(_, Operand::SPOffset) => Trailing::Displace,
+      Opcode::JP(_, Operand::Imm16) | Opcode::CALL(_) => Trailing::Two,
+      Opcode::LD(Operand::Absolute, _) | Opcode::LD(_, Operand::Absolute) => Trailing::Two,
+      Opcode::LD(_, Operand::Imm16) => Trailing::Two,
+      Opcode::LD(_, Operand::Imm8) | Opcode::LDH(_, _) => Trailing::One,
+      Opcode::ADD(_, Operand::Imm8) | Opcode::ADC(Operand::Imm8) | Opcode::SUB(Operand::Imm8)
+      | Opcode::SBC(Operand::Imm8) | Opcode::AND(Operand::Imm8) | Opcode::XOR(Operand::Imm8)
+      | Opcode::OR(Operand::Imm8) | Opcode::CP(Operand::Imm8) => Trailing::One,
+      _ => Trailing::None,
+    }
   }
+}
 
-  struct Immed {
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::decode::DecodeErr;
 
+  #[test]
+  fn stop_takes_two_bytes() {
+    let stop = Instr::decode(&[0x10, 0x00]).unwrap();
+    assert_eq!(stop.opcode(), Some(&Opcode::STOP));
+    assert_eq!(stop.len(), 2);
+    match stop {
+      Instr::Single { immed, .. } => assert_eq!(immed, Some(Immediate::One(0x00))),
+      _ => panic!("expected STOP, got {:?}", stop),
+    }
+
+    assert_eq!(Instr::decode(&[0x10]), Err(DecodeErr::Truncated(2)));
   }
 
-  impl Decoder<Start> {
-    pub fn new(bytes: [u8; 4]) -> Decoder<Start> {
-      Decoder {
-        bytes,
-        state: Start {},
+  #[test]
+  fn corrupted_stop_keeps_its_second_byte() {
+    let stop = Instr::decode(&[0x10, 0x01]).unwrap();
+    assert_eq!(stop.opcode(), Some(&Opcode::STOP));
+    assert_eq!(stop.len(), 2);
+    match stop {
+      Instr::Single { immed, .. } => assert_eq!(immed, Some(Immediate::One(0x01))),
+      _ => panic!("expected STOP, got {:?}", stop),
+    }
+  }
+
+  #[test]
+  fn hl_operands_cost_a_memory_access() {
+    let cycles = |bytes: &[u8]| Instr::decode(bytes).unwrap().cycles(false);
+
+    // ADD A,B / ADD A,(HL)
+    assert_eq!((cycles(&[0x80]), cycles(&[0x86])), (4, 8));
+    // LD B,C / LD B,(HL) / LD (HL),B
+    assert_eq!((cycles(&[0x41]), cycles(&[0x46]), cycles(&[0x70])), (4, 8, 8));
+    // INC B / INC (HL), which both reads and writes
+    assert_eq!((cycles(&[0x04]), cycles(&[0x34])), (4, 12));
+    // RLC B / RLC (HL)
+    assert_eq!((cycles(&[0xCB, 0x00]), cycles(&[0xCB, 0x06])), (8, 16));
+    // BIT 0,B / BIT 0,(HL), which only reads
+    assert_eq!((cycles(&[0xCB, 0x40]), cycles(&[0xCB, 0x46])), (8, 12));
+  }
+
+  #[test]
+  fn every_byte_decodes() {
+    for op in 0..=0xFFu8 {
+      match Instr::decode(&[op, 0x00, 0x00]) {
+        Ok(Instr::Illegal(b)) if decode::ILLEGAL_OPCODES.contains(&op) => assert_eq!(b, op),
+        Ok(instr @ Instr::Single { .. }) if !decode::ILLEGAL_OPCODES.contains(&op) => {
+          assert!(instr.len() <= 3, "{:02X} decoded as {:?}", op, instr);
+        },
+        x => panic!("unexpected decoding of {:02X}: {:?}", op, x),
+      }
+    }
+
+    for op in 0..=0xFFu8 {
+      match Instr::decode(&[PREFIX_CB, op]) {
+        Ok(instr @ Instr::Single { prefix: Some(Prefix::CB), .. }) => assert_eq!(instr.len(), 2),
+        x => panic!("unexpected decoding of CB {:02X}: {:?}", op, x),
       }
     }
   }
 
-  impl From<Decoder<Start>> for Decoder<Prefix> {
-    fn from(dec: Decoder<Start>) -> Decoder<Prefix> {
-      unimplemented!()
+  #[test]
+  fn flag_effects_of_add_and_and_daa() {
+    use self::FlagEffect::{Calculated as X, Clear as O, Set as I, Unchanged as U};
+    let effects = |bytes: &[u8]| {
+      let fx = Instr::decode(bytes).unwrap().opcode().unwrap().flag_effects();
+      (fx.zero, fx.add_sub, fx.half_carry, fx.carry)
+    };
+
+    // ADD A,B: Z 0 H C
+    assert_eq!(effects(&[0x80]), (X, O, X, X));
+    // ADD HL,BC: - 0 H C
+    assert_eq!(effects(&[0x09]), (U, O, X, X));
+    // AND B: Z 0 1 0
+    assert_eq!(effects(&[0xA0]), (X, O, I, O));
+    // DAA: Z - 0 C
+    assert_eq!(effects(&[0x27]), (X, U, O, X));
+  }
+
+  #[test]
+  fn mnemonics_name_just_the_operation() {
+    assert_eq!(Opcode::NOP.mnemonic(), "NOP");
+    assert_eq!(Opcode::LD(Operand::Reg8(R8::A), Operand::Imm8).mnemonic(), "LD");
+    assert_eq!(Opcode::ADD(Operand::Reg16(R16::HL), Operand::Reg16(R16::BC)).mnemonic(), "ADD");
+    assert_eq!(Opcode::JP(None, Operand::Imm16).mnemonic(), "JP");
+    assert_eq!(Opcode::JR(Some(Cond::NZ)).mnemonic(), "JR");
+    assert_eq!(Opcode::BIT(7, Operand::Reg8(R8::H)).mnemonic(), "BIT");
+    assert_eq!(Opcode::RETI.mnemonic(), "RETI");
+  }
+
+  #[test]
+  fn decode_then_encode_gives_back_the_bytes() {
+    for &(lo, hi) in [(0x00, 0x00), (0x34, 0x12), (0x80, 0xFF), (0xFE, 0x7F)].iter() {
+      for op in 0..=0xFFu8 {
+        let bytes = [op, lo, hi];
+        let instr = Instr::decode(&bytes).unwrap();
+        assert_eq!(&instr.encode()[..], &bytes[.. instr.len()], "{:?}", instr);
+        assert_eq!(Instr::decode(&instr.encode()), Ok(instr));
+      }
+    }
+
+    for op in 0..=0xFFu8 {
+      let bytes = [PREFIX_CB, op];
+      assert_eq!(Instr::decode(&bytes).unwrap().encode(), bytes.to_vec());
     }
   }
 
-  impl DecoderState for Start {}
-  impl DecoderState for Prefix {}
-  impl DecoderState for DblPrefix {}
-  impl DecoderState for Opcode {}
-  impl DecoderState for Displace {}
-  impl DecoderState for Immed {}
+  #[test]
+  fn unencodable_instructions_encode_to_nothing() {
+    let instr = Instr::Single {
+      prefix: None,
+      opcode: Opcode::LD(Operand::Imm8, Operand::Imm8),
+      displace: None,
+      immed: None,
+    };
+    assert!(instr.encode().is_empty());
+  }
+
+  #[test]
+  fn effects_of_ld_add_and_nop() {
+    let effects = |bytes: &[u8]| Instr::decode(bytes).unwrap().effects();
+    let flags = vec![Location::Zero, Location::AddSub, Location::HalfCarry, Location::Carry];
+
+    let ld = effects(&[0x78]);
+    assert_eq!(ld.reads, vec![Location::Reg(R8::B)]);
+    assert_eq!(ld.writes, vec![Location::Reg(R8::A)]);
+
+    let add = effects(&[0x80]);
+    assert_eq!(add.reads, vec![Location::Reg(R8::A), Location::Reg(R8::B)]);
+    let mut writes = vec![Location::Reg(R8::A)];
+    writes.extend(flags);
+    assert_eq!(add.writes, writes);
+
+    // LD A,(HL)
+    let load = effects(&[0x7E]);
+    assert!(load.reads.contains(&Location::Memory));
+    assert!(load.reads.contains(&Location::Reg(R8::H)) && load.reads.contains(&Location::Reg(R8::L)));
+
+    assert_eq!(effects(&[0x00]), InstrEffects::default());
+  }
+
+  #[test]
+  fn illegal_opcodes_match_the_documented_list() {
+    let documented = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+    assert_eq!(Instr::illegal_opcodes(), &documented[..]);
+    for &op in Instr::illegal_opcodes() {
+      assert_eq!(Instr::decode(&[op]), Ok(Instr::Illegal(op)));
+    }
+  }
 }
@@ -0,0 +1,72 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+/// Interrupt sources, in priority order (VBlank is serviced first).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+  VBlank,
+  Stat,
+  Timer,
+  Serial,
+  Joypad
+}
+
+impl Interrupt {
+
+  /// Address of the handler the CPU jumps to when servicing this interrupt.
+  pub fn vector(self) -> u16 {
+    match self {
+      Interrupt::VBlank => 0x40,
+      Interrupt::Stat => 0x48,
+      Interrupt::Timer => 0x50,
+      Interrupt::Serial => 0x58,
+      Interrupt::Joypad => 0x60
+    }
+  }
+
+  /// Bit index of this interrupt in the IE (0xFFFF) and IF (0xFF0F) registers.
+  pub fn bit(self) -> u8 {
+    match self {
+      Interrupt::VBlank => 0,
+      Interrupt::Stat => 1,
+      Interrupt::Timer => 2,
+      Interrupt::Serial => 3,
+      Interrupt::Joypad => 4
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn vectors_and_bits() {
+    let expected = [
+      (Interrupt::VBlank, 0x40, 0),
+      (Interrupt::Stat, 0x48, 1),
+      (Interrupt::Timer, 0x50, 2),
+      (Interrupt::Serial, 0x58, 3),
+      (Interrupt::Joypad, 0x60, 4),
+    ];
+    for &(int, vector, bit) in expected.iter() {
+      assert_eq!(int.vector(), vector);
+      assert_eq!(int.bit(), bit);
+    }
+  }
+}
@@ -15,38 +15,310 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-mod clock;
-mod instr;
-mod register;
+pub mod clock;
+pub mod disasm;
+mod exec;
+pub mod instr;
+pub mod interrupt;
+pub mod register;
 
-use super::cart;
+use alloc::string::String;
+use core::ops::RangeInclusive;
+use core::result;
 
+use super::bus::{Bus, WatchHit};
+use super::cart::Cartridge;
+use super::model::Model;
+
+use self::clock::Clock;
+use self::instr::Instr;
+use self::instr::decode::DecodeErr;
 use self::register::*;
 
+pub type Result<T> = result::Result<T, StepErr>;
+
+#[derive(Debug)]
+pub enum StepErr {
+  Decode(DecodeErr),
+  Illegal(u8),
+  Unimplemented(Instr),
+  /// The instruction completed but touched a watched address; PC is already
+  /// past it.
+  Watchpoint(WatchHit),
+  /// A push or pop would have accessed this address, outside the stack guard.
+  /// Nothing was read or written.
+  StackGuard(u16),
+}
+
+/// Why `Processor::start` gave up.
+#[derive(Debug)]
+pub enum EmuErr {
+  /// The instruction at the given PC failed to execute.
+  Step(u16, StepErr),
+}
+
+/// How `Processor::step` advances the clock and peripherals.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecMode {
+  /// Runs the whole instruction, then advances everything by its full length
+  /// at once. Fastest.
+  #[default]
+  InstructionAtomic,
+  /// Advances one M-cycle at a time, so peripherals can be observed between
+  /// the memory accesses of an instruction.
+  TCycleStepped,
+}
+
 pub struct Processor {
-  reg_af: CompositeReg,
-  reg_bc: CompositeReg,
-  reg_de: CompositeReg,
-  reg_hl: CompositeReg,
-  reg_sp: Reg,
-  reg_pc: Reg
+  regs: RegisterFile,
+  /// Interrupt master enable.
+  ime: bool,
+  /// Instructions left to execute before a pending EI sets IME; 0 if none.
+  ime_pending: u8,
+  stopped: bool,
+  stack_guard: Option<RangeInclusive<u16>>,
+  mode: ExecMode,
 }
 
+impl Default for Processor {
+  fn default() -> Processor {
+    Processor::new()
+  }
+}
 
 impl Processor {
   pub fn new() -> Processor {
     Processor {
-      reg_af: CompositeReg::new(0),
-      reg_bc: CompositeReg::new(0),
-      reg_de: CompositeReg::new(0),
-      reg_hl: CompositeReg::new(0),
-      reg_pc: Reg::new(0),
-      reg_sp: Reg::new(0)
+      regs: RegisterFile::new(),
+      ime: false,
+      ime_pending: 0,
+      stopped: false,
+      stack_guard: None,
+      mode: ExecMode::default(),
+    }
+  }
+
+  pub fn regs(&self) -> &RegisterFile {
+    &self.regs
+  }
+
+  pub fn regs_mut(&mut self) -> &mut RegisterFile {
+    &mut self.regs
+  }
+
+  /// Puts the CPU in the state the boot ROM leaves it in, ready to run `cart`
+  /// from its entry point at 0x0100.
+  pub fn skip_boot(&mut self, cart: &Cartridge) {
+    let model = if cart.is_cgb() { Model::CGB } else { Model::DMG };
+    self.reset(model);
+  }
+
+  /// Restores the state `model` is in once its boot ROM hands over, without
+  /// reallocating, e.g. when a new cartridge is loaded. Settings such as the
+  /// stack guard and execution mode are kept.
+  pub fn reset(&mut self, model: Model) {
+    self.regs = RegisterFile::post_boot(model);
+    self.ime = false;
+    self.ime_pending = 0;
+    self.stopped = false;
+  }
+
+  pub fn exec_mode(&self) -> ExecMode {
+    self.mode
+  }
+
+  pub fn set_exec_mode(&mut self, mode: ExecMode) {
+    self.mode = mode;
+  }
+
+  /// Fails any push or pop that would read or write outside `range`, to catch
+  /// a runaway stack. Off by default.
+  pub fn set_stack_guard(&mut self, range: RangeInclusive<u16>) {
+    self.stack_guard = Some(range);
+  }
+
+  pub fn clear_stack_guard(&mut self) {
+    self.stack_guard = None;
+  }
+
+  /// Registers, flags and interrupt state laid out for a debugger, e.g.
+  ///
+  /// ```text
+  /// AF=01B0 BC=0013 DE=00D8 HL=014D
+  /// SP=FFFE PC=0100
+  /// Z N H C flags: 1 0 1 1 IME=0
+  /// ```
+  pub fn format_state(&self) -> String {
+    let r = &self.regs;
+    let f = r.af().lower();
+    let bit = |flag| if f.is_set(flag) { 1 } else { 0 };
+    format!("AF={:04X} BC={:04X} DE={:04X} HL={:04X}\nSP={:04X} PC={:04X}\n\
+             Z N H C flags: {} {} {} {} IME={}",
+            r.af().get(), r.bc().get(), r.de().get(), r.hl().get(),
+            r.sp().get(), r.pc().get(),
+            bit(Flag::Zero), bit(Flag::AddSub), bit(Flag::HalfCarry), bit(Flag::Carry),
+            self.ime as u8)
+  }
+
+  /// Interrupt master enable.
+  pub fn ime(&self) -> bool {
+    self.ime
+  }
+
+  /// Whether an EI has yet to take effect: IME turns on after the instruction
+  /// following it.
+  pub fn ime_pending(&self) -> bool {
+    self.ime_pending > 0
+  }
+
+  /// Whether a STOP put the CPU into low-power mode.
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+
+  /// Runs until the CPU stops, an instruction fails, or at least `max_cycles`
+  /// T-cycles have elapsed.
+  pub fn start<B: Bus + ?Sized>(&mut self, bus: &mut B, clock: &mut Clock,
+                                max_cycles: Option<usize>) -> result::Result<(), EmuErr> {
+    let mut elapsed = 0;
+    while max_cycles.is_none_or(|max| elapsed < max) {
+      if self.stopped {
+        return Ok(());
+      }
+      let pc = self.regs.pc().get();
+      match self.step(bus, clock) {
+        Ok(cycles) => elapsed += cycles,
+        Err(e) => return Err(EmuErr::Step(pc, e)),
+      }
     }
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use hw::bus::tests::FlatBus;
+  use hw::cart::regions;
+  use hw::cart::tests::{fix_checksums, rom};
+  use hw::cpu::clock::Frequency;
+  use super::*;
+
+  #[test]
+  fn skip_boot_lands_on_the_entry_point() {
+    let cart = Cartridge::new(rom("DMG", 0x00, 0x00, 0x00)).unwrap();
+    let mut cpu = Processor::new();
+    cpu.skip_boot(&cart);
+
+    assert_eq!(cpu.regs().pc().get(), 0x0100);
+    assert_eq!(cpu.regs().sp().get(), 0xFFFE);
+    assert_eq!(cpu.regs().af().get(), 0x01B0);
+    assert!(!cpu.ime());
   }
 
-  pub fn start(&mut self) {
+  #[test]
+  fn skip_boot_uses_the_cgb_state_for_cgb_cartridges() {
+    let mut bytes = rom("CGB", 0x00, 0x00, 0x00);
+    bytes[regions::META_CGB_FLAG.0] = 0x80;
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+    let mut cpu = Processor::new();
+    cpu.skip_boot(&cart);
 
+    assert_eq!(cpu.regs().pc().get(), 0x0100);
+    assert_eq!(cpu.regs().af().get(), 0x1180);
   }
 
+  #[test]
+  fn start_returns_at_the_cycle_cap() {
+    // NOP; JR -2
+    let mut bus = FlatBus::with_program(0x0100, &[0x00, 0x18, 0xFE]);
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.start(&mut bus, &mut clock, Some(1000)).unwrap();
+    assert_eq!(cpu.regs().pc().get(), 0x0101);
+    assert!(clock.time() >= 1000 && clock.time() < 1000 + 12);
+  }
+
+  #[test]
+  fn start_reports_the_failing_instruction() {
+    let mut bus = FlatBus::with_program(0x0100, &[0x00, 0xD3]);
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    let mut clock = Clock::new(Frequency::Single);
+
+    match cpu.start(&mut bus, &mut clock, None) {
+      Err(EmuErr::Step(0x0101, StepErr::Illegal(0xD3))) => (),
+      x => panic!("expected an illegal opcode at 0x0101, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn start_returns_once_stopped() {
+    let mut bus = FlatBus::with_program(0x0100, &[0x10, 0x00]);
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    let mut clock = Clock::new(Frequency::Single);
+
+    cpu.start(&mut bus, &mut clock, None).unwrap();
+    assert!(cpu.is_stopped());
+  }
+
+  #[test]
+  fn reset_matches_a_new_processor() {
+    // EI; STOP
+    let mut bus = FlatBus::with_program(0x0100, &[0xFB, 0x10, 0x00]);
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    cpu.regs_mut().bc_mut().set(0x1234);
+    let mut clock = Clock::new(Frequency::Single);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(cpu.ime_pending());
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(cpu.ime() && cpu.is_stopped());
+
+    for &model in &[Model::DMG, Model::CGB] {
+      cpu.reset(model);
+      let mut fresh = Processor::new();
+      *fresh.regs_mut() = RegisterFile::post_boot(model);
+      assert_eq!(cpu.regs(), fresh.regs());
+      assert_eq!(cpu.ime(), fresh.ime());
+      assert_eq!(cpu.ime_pending(), fresh.ime_pending());
+      assert_eq!(cpu.is_stopped(), fresh.is_stopped());
+    }
+    assert_eq!(cpu.regs().af().get(), 0x1180);
+  }
+
+  #[test]
+  fn format_state_lays_out_registers_and_flags() {
+    let cart = Cartridge::new(rom("DMG", 0x00, 0x00, 0x00)).unwrap();
+    let mut cpu = Processor::new();
+    cpu.skip_boot(&cart);
+    assert_eq!(cpu.format_state(),
+               "AF=01B0 BC=0013 DE=00D8 HL=014D\nSP=FFFE PC=0100\nZ N H C flags: 1 0 1 1 IME=0");
+
+    cpu.regs_mut().af_mut().set(0x1250);
+    cpu.regs_mut().pc_mut().set(0xC000);
+    assert_eq!(cpu.format_state(),
+               "AF=1250 BC=0013 DE=00D8 HL=014D\nSP=FFFE PC=C000\nZ N H C flags: 0 1 0 1 IME=0");
+  }
+
+  #[test]
+  fn ime_accessors_show_the_delayed_enable() {
+    // EI; NOP; DI
+    let mut bus = FlatBus::with_program(0x0100, &[0xFB, 0x00, 0xF3]);
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    let mut clock = Clock::new(Frequency::Single);
+    assert!(!cpu.ime() && !cpu.ime_pending());
+
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(!cpu.ime() && cpu.ime_pending());
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(cpu.ime() && !cpu.ime_pending());
+    cpu.step(&mut bus, &mut clock).unwrap();
+    assert!(!cpu.ime() && !cpu.ime_pending());
+  }
 }
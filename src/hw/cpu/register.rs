@@ -15,6 +15,8 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use hw::model::Model;
+
 pub trait Register<T> {
   fn get(&self) -> T;
   fn set(&mut self, new_value: T);
@@ -24,15 +26,39 @@ pub trait FlagRegister: Register<u8> {
   fn is_set(&self, flag: Flag) -> bool;
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Reg {
   value: u8,
+  /// Bits that exist in hardware; the rest always read as 0.
+  mask: u8,
+}
+
+/// A 16-bit register with no addressable halves, used for SP and PC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Reg16 {
+  value: u16,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CompositeReg {
   upper: Reg,
   lower: Reg,
 }
 
+/// The CPU's architectural registers, kept apart from `Processor` so they can be
+/// snapshotted and compared on their own.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisterFile {
+  af: CompositeReg,
+  bc: CompositeReg,
+  de: CompositeReg,
+  hl: CompositeReg,
+  sp: Reg16,
+  pc: Reg16,
+}
+
+/// The bits of F that hold flags.
+const F_MASK: u8 = 0xF0;
 
 pub enum Flag {
   Zero = 1 << 7,
@@ -43,7 +69,7 @@ pub enum Flag {
 
 impl Register<u16> for CompositeReg {
   fn get(&self) -> u16 {
-    ((self.upper.get() << 8 + self.lower.get()) as u16)
+    ((self.upper.get() as u16) << 8) | self.lower.get() as u16
   }
 
   fn set(&mut self, new_value: u16) {
@@ -80,14 +106,82 @@ impl CompositeReg {
     x.set(initial);
     x
   }
+
+  /// The AF pair, whose lower half F has no low nibble: `set(0x120F)` then
+  /// `get()` gives `0x1200`, however F is written.
+  pub fn new_af(initial: u16) -> Self {
+    let mut x = CompositeReg {
+      upper: Reg::new(0),
+      lower: Reg::with_mask(0, F_MASK),
+    };
+    x.set(initial);
+    x
+  }
+}
+
+impl Reg16 {
+  pub fn new(initial: u16) -> Self {
+    Reg16 {
+      value: initial,
+    }
+  }
+
+  /// Adds `n`, wrapping past 0xFFFF to 0x0000.
+  pub fn wrapping_add(&mut self, n: u16) {
+    self.value = self.value.wrapping_add(n);
+  }
+
+  /// Subtracts `n`, wrapping below 0x0000 to 0xFFFF.
+  pub fn wrapping_sub(&mut self, n: u16) {
+    self.value = self.value.wrapping_sub(n);
+  }
+}
+
+impl Register<u16> for Reg16 {
+  fn get(&self) -> u16 {
+    self.value
+  }
+
+  fn set(&mut self, new_value: u16) {
+    self.value = new_value;
+  }
 }
 
 impl Reg  {
   pub fn new(initial: u8) -> Self {
+    Reg::with_mask(initial, 0xFF)
+  }
+
+  /// A register with only the bits in `mask`. Writes to the others are dropped.
+  pub fn with_mask(initial: u8, mask: u8) -> Self {
     Reg {
-      value: initial,
+      value: initial & mask,
+      mask,
     }
   }
+
+  /// Whether bit `n` is set. `n` must be 0-7; in release builds only its low
+  /// three bits are used, as for the other bit methods.
+  pub fn bit(&self, n: u8) -> bool {
+    self.value & Reg::mask(n) != 0
+  }
+
+  pub fn set_bit(&mut self, n: u8) {
+    self.value |= Reg::mask(n) & self.mask;
+  }
+
+  pub fn clear_bit(&mut self, n: u8) {
+    self.value &= !Reg::mask(n);
+  }
+
+  pub fn toggle_bit(&mut self, n: u8) {
+    self.value ^= Reg::mask(n) & self.mask;
+  }
+
+  fn mask(n: u8) -> u8 {
+    debug_assert!(n < 8, "bit {} out of range for an 8-bit register", n);
+    1 << (n & 0x7)
+  }
 }
 
 impl Register<u8> for Reg {
@@ -96,7 +190,7 @@ impl Register<u8> for Reg {
   }
 
   fn set(&mut self, new_value: u8) {
-    self.value = new_value;
+    self.value = new_value & self.mask;
   }
 }
 
@@ -105,3 +199,218 @@ impl FlagRegister for Reg {
     (self.get() & (flag as u8)) != 0
   }
 }
+
+impl Default for RegisterFile {
+  fn default() -> RegisterFile {
+    RegisterFile::new()
+  }
+}
+
+impl RegisterFile {
+  pub fn new() -> RegisterFile {
+    RegisterFile {
+      af: CompositeReg::new_af(0),
+      bc: CompositeReg::new(0),
+      de: CompositeReg::new(0),
+      hl: CompositeReg::new(0),
+      sp: Reg16::new(0),
+      pc: Reg16::new(0),
+    }
+  }
+
+  /// Register contents as the boot ROM leaves them on `model`, with PC at the
+  /// cartridge entry point.
+  pub fn post_boot(model: Model) -> RegisterFile {
+    let (af, bc, de, hl) = match model {
+      Model::DMG => (0x01B0, 0x0013, 0x00D8, 0x014D),
+      Model::MGB => (0xFFB0, 0x0013, 0x00D8, 0x014D),
+      Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+      Model::CGB => (0x1180, 0x0000, 0xFF56, 0x000D),
+    };
+
+    RegisterFile {
+      af: CompositeReg::new_af(af),
+      bc: CompositeReg::new(bc),
+      de: CompositeReg::new(de),
+      hl: CompositeReg::new(hl),
+      sp: Reg16::new(0xFFFE),
+      pc: Reg16::new(0x0100),
+    }
+  }
+
+  pub fn af(&self) -> &CompositeReg {
+    &self.af
+  }
+
+  pub fn af_mut(&mut self) -> &mut CompositeReg {
+    &mut self.af
+  }
+
+  pub fn bc(&self) -> &CompositeReg {
+    &self.bc
+  }
+
+  pub fn bc_mut(&mut self) -> &mut CompositeReg {
+    &mut self.bc
+  }
+
+  pub fn de(&self) -> &CompositeReg {
+    &self.de
+  }
+
+  pub fn de_mut(&mut self) -> &mut CompositeReg {
+    &mut self.de
+  }
+
+  pub fn hl(&self) -> &CompositeReg {
+    &self.hl
+  }
+
+  pub fn hl_mut(&mut self) -> &mut CompositeReg {
+    &mut self.hl
+  }
+
+  pub fn sp(&self) -> &Reg16 {
+    &self.sp
+  }
+
+  pub fn sp_mut(&mut self) -> &mut Reg16 {
+    &mut self.sp
+  }
+
+  pub fn pc(&self) -> &Reg16 {
+    &self.pc
+  }
+
+  pub fn pc_mut(&mut self) -> &mut Reg16 {
+    &mut self.pc
+  }
+
+  /// Adds `n` to PC, wrapping past 0xFFFF to 0x0000 as the hardware does.
+  pub fn incr_pc(&mut self, n: u16) {
+    self.pc.wrapping_add(n);
+  }
+
+  /// Adds `n` to SP, wrapping past 0xFFFF to 0x0000.
+  pub fn incr_sp(&mut self, n: u16) {
+    self.sp.wrapping_add(n);
+  }
+
+  /// Subtracts `n` from SP, wrapping below 0x0000 to 0xFFFF.
+  pub fn decr_sp(&mut self, n: u16) {
+    self.sp.wrapping_sub(n);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn register_file_clones_compare_by_value() {
+    let mut regs = RegisterFile::new();
+    regs.bc_mut().set(0x1234);
+    regs.pc_mut().set(0x50);
+
+    let mut copy = regs.clone();
+    assert!(copy == regs);
+
+    copy.hl_mut().set(0xC000);
+    assert!(copy != regs);
+    assert_eq!(regs.hl().upper().get(), 0x00);
+  }
+
+  #[test]
+  fn pc_and_sp_hold_16_bits_and_wrap() {
+    let mut regs = RegisterFile::new();
+    regs.pc_mut().set(0x0150);
+    assert_eq!(regs.pc().get(), 0x0150);
+
+    regs.pc_mut().set(0xFFFF);
+    regs.incr_pc(2);
+    assert_eq!(regs.pc().get(), 0x0001);
+
+    regs.decr_sp(1);
+    assert_eq!(regs.sp().get(), 0xFFFF);
+    regs.incr_sp(1);
+    assert_eq!(regs.sp().get(), 0x0000);
+  }
+
+  #[test]
+  fn reg16_round_trips_and_wraps() {
+    let mut reg = Reg16::new(0xBEEF);
+    assert_eq!(reg.get(), 0xBEEF);
+
+    reg.set(0xFFFE);
+    reg.wrapping_add(3);
+    assert_eq!(reg.get(), 0x0001);
+    reg.wrapping_sub(2);
+    assert_eq!(reg.get(), 0xFFFF);
+  }
+
+  #[test]
+  fn composite_regs_compare_by_value() {
+    let a = CompositeReg::new(0xBEEF);
+    let mut b = CompositeReg::new(0xBE00);
+    assert_ne!(a, b);
+
+    b.lower_mut().set(0xEF);
+    assert_eq!(a, b);
+    assert_eq!(a.clone(), b);
+
+    let upper = *a.upper();
+    assert_eq!(upper, Reg::new(0xBE));
+  }
+
+  #[test]
+  fn bit_accessors_cover_every_position() {
+    for n in 0..8 {
+      let mut reg = Reg::new(0x00);
+      reg.set_bit(n);
+      assert_eq!(reg.get(), 1 << n);
+      assert!(reg.bit(n));
+      assert!((0..8).filter(|&m| m != n).all(|m| !reg.bit(m)));
+
+      reg.toggle_bit(n);
+      assert_eq!(reg.get(), 0x00);
+      reg.toggle_bit(n);
+      assert!(reg.bit(n));
+
+      let mut full = Reg::new(0xFF);
+      full.clear_bit(n);
+      assert_eq!(full.get(), !(1 << n));
+    }
+  }
+
+  #[test]
+  fn bit_accessors_respect_the_register_mask() {
+    let mut reg = Reg::with_mask(0x00, 0xF0);
+    reg.set_bit(0);
+    reg.toggle_bit(1);
+    reg.set_bit(7);
+    assert_eq!(reg.get(), 0x80);
+  }
+
+  #[cfg(debug_assertions)]
+  #[test]
+  #[should_panic(expected = "out of range")]
+  fn bit_past_seven_is_caught_in_debug_builds() {
+    Reg::new(0xFF).bit(8);
+  }
+
+  #[test]
+  fn af_drops_the_low_nibble_of_f() {
+    let mut af = CompositeReg::new_af(0x01FF);
+    assert_eq!(af.get(), 0x01F0);
+
+    af.set(0x120F);
+    assert_eq!(af.get(), 0x1200);
+    af.lower_mut().set(0xAB);
+    assert_eq!(af.get(), 0x12A0);
+
+    // Other pairs keep every bit.
+    let mut bc = CompositeReg::new(0x120F);
+    bc.set(0x34CD);
+    assert_eq!(bc.get(), 0x34CD);
+  }
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use alloc::vec::Vec;
+use core::cmp;
+use core::ops::RangeInclusive;
+
+use super::cart::MBCNum;
+use super::rtc::RTC;
+
+const ROM_BANK_BYTES: usize = 0x4000;
+const RAM_BANK_BYTES: usize = 0x2000;
+
+/// A register of a memory bank controller, selected by writing to ROM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MBCRegister {
+  RAMEnable,
+  ROMBank,
+  /// MBC5 only: bit 8 of the ROM bank.
+  ROMBankHigh,
+  RAMBank,
+  Mode,
+}
+
+/// Address ranges that select each register of a controller of type `kind`.
+/// MBC2 decodes its two registers from the same range by address bit 8, set
+/// for the ROM bank and clear for RAM enable.
+pub fn register_ranges(kind: &MBCNum) -> Vec<(RangeInclusive<u16>, MBCRegister)> {
+  match *kind {
+    MBCNum::N1 => vec![
+      (0x0000 ..= 0x1FFF, MBCRegister::RAMEnable),
+      (0x2000 ..= 0x3FFF, MBCRegister::ROMBank),
+      (0x4000 ..= 0x5FFF, MBCRegister::RAMBank),
+      (0x6000 ..= 0x7FFF, MBCRegister::Mode),
+    ],
+    MBCNum::N2 => vec![
+      (0x0000 ..= 0x3FFF, MBCRegister::RAMEnable),
+      (0x0000 ..= 0x3FFF, MBCRegister::ROMBank),
+    ],
+    MBCNum::N3 => vec![
+      (0x0000 ..= 0x1FFF, MBCRegister::RAMEnable),
+      (0x2000 ..= 0x3FFF, MBCRegister::ROMBank),
+      (0x4000 ..= 0x5FFF, MBCRegister::RAMBank),
+    ],
+    MBCNum::N5 => vec![
+      (0x0000 ..= 0x1FFF, MBCRegister::RAMEnable),
+      (0x2000 ..= 0x2FFF, MBCRegister::ROMBank),
+      (0x3000 ..= 0x3FFF, MBCRegister::ROMBankHigh),
+      (0x4000 ..= 0x5FFF, MBCRegister::RAMBank),
+    ],
+  }
+}
+
+/// Bank-switching state of a cartridge's memory bank controller. Writes to
+/// 0x0000-0x7FFF land here instead of in ROM.
+#[derive(Clone, Debug)]
+pub struct MBC {
+  kind: Option<MBCNum>,
+  /// Banks the ROM holds. Selections past the end wrap around.
+  rom_banks: usize,
+  rom_bank: usize,
+  ram_bank: usize,
+  /// External RAM ignores accesses until enabled, except without an MBC.
+  ram_enabled: bool,
+  /// MBC1 only: when set, the 2-bit register also banks 0x0000-0x3FFF and
+  /// external RAM instead of just the upper ROM bank bits.
+  advanced_mode: bool,
+  /// MBC3 only, and only on cartridges that have one.
+  rtc: Option<RTC>,
+  /// Clock register mapped at 0xA000-0xBFFF in place of RAM, if any.
+  rtc_select: Option<usize>,
+}
+
+impl MBC {
+
+  /// A controller of type `kind` driving `rom_bytes` of ROM, or plain ROM
+  /// with no banking if `None`.
+  pub fn new(kind: Option<MBCNum>, rom_bytes: usize) -> MBC {
+    MBC {
+      rom_banks: cmp::max(rom_bytes.div_ceil(ROM_BANK_BYTES), 1),
+      rom_bank: 1,
+      ram_bank: 0,
+      ram_enabled: kind.is_none(),
+      advanced_mode: false,
+      rtc: None,
+      rtc_select: None,
+      kind,
+    }
+  }
+
+  /// Adds an MBC3 real-time clock.
+  pub fn with_rtc(mut self, rtc: RTC) -> MBC {
+    self.rtc = Some(rtc);
+    self
+  }
+
+  pub fn rtc(&self) -> Option<&RTC> {
+    self.rtc.as_ref()
+  }
+
+  pub fn rtc_mut(&mut self) -> Option<&mut RTC> {
+    self.rtc.as_mut()
+  }
+
+  pub fn kind(&self) -> Option<&MBCNum> {
+    self.kind.as_ref()
+  }
+
+  /// Whether external RAM currently responds to reads and writes.
+  pub fn ram_enabled(&self) -> bool {
+    self.ram_enabled
+  }
+
+  /// Handles a write to the ROM area.
+  pub fn write(&mut self, addr: u16, value: u8) {
+    match self.kind {
+      None => {},
+      Some(MBCNum::N1) => match addr {
+        0x0000 ..= 0x1FFF => self.ram_enabled = enables_ram(value),
+        0x2000 ..= 0x3FFF => self.rom_bank = nonzero(value & 0x1F),
+        0x4000 ..= 0x5FFF => self.ram_bank = (value & 0x03) as usize,
+        0x6000 ..= 0x7FFF => self.advanced_mode = value & 0x01 != 0,
+        _ => {},
+      },
+      // Address bit 8 picks the register: set for the ROM bank, clear for RAM enable.
+      Some(MBCNum::N2) => match addr {
+        0x0000 ..= 0x3FFF if addr & 0x0100 != 0 => self.rom_bank = nonzero(value & 0x0F),
+        0x0000 ..= 0x3FFF => self.ram_enabled = enables_ram(value),
+        _ => {},
+      },
+      Some(MBCNum::N3) => match addr {
+        0x0000 ..= 0x1FFF => self.ram_enabled = enables_ram(value),
+        0x2000 ..= 0x3FFF => self.rom_bank = nonzero(value & 0x7F),
+        // 0x08-0x0C map a clock register instead of a RAM bank.
+        0x4000 ..= 0x5FFF => match value {
+          0x08 ..= 0x0C if self.rtc.is_some() => self.rtc_select = Some((value - 0x08) as usize),
+          _ => {
+            self.rtc_select = None;
+            self.ram_bank = (value & 0x03) as usize;
+          },
+        },
+        0x6000 ..= 0x7FFF => if let Some(ref mut rtc) = self.rtc {
+          rtc.write_latch(value);
+        },
+        _ => {},
+      },
+      // MBC5 can map bank 0 into 0x4000-0x7FFF.
+      Some(MBCNum::N5) => match addr {
+        0x0000 ..= 0x1FFF => self.ram_enabled = enables_ram(value),
+        0x2000 ..= 0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as usize,
+        0x3000 ..= 0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value as usize & 0x01) << 8),
+        0x4000 ..= 0x5FFF => self.ram_bank = (value & 0x0F) as usize,
+        _ => {},
+      },
+    }
+  }
+
+  /// Reads the selected clock register, if one is mapped instead of RAM.
+  pub fn read_rtc(&self) -> Option<u8> {
+    match (self.rtc_select, &self.rtc) {
+      (Some(reg), Some(rtc)) => Some(rtc.read(reg)),
+      _ => None,
+    }
+  }
+
+  /// Writes the selected clock register. Returns false, doing nothing, if RAM
+  /// is mapped instead.
+  pub fn write_rtc(&mut self, value: u8) -> bool {
+    match (self.rtc_select, &mut self.rtc) {
+      (Some(reg), Some(rtc)) => {
+        rtc.write(reg, value);
+        true
+      },
+      _ => false,
+    }
+  }
+
+  /// Advances the clock, if there is one, by `cycles` CPU cycles.
+  pub fn tick(&mut self, cycles: usize) {
+    if let Some(ref mut rtc) = self.rtc {
+      rtc.tick(cycles);
+    }
+  }
+
+  /// Flat ROM offset that `addr` (0x0000-0x7FFF) currently maps to.
+  pub fn rom_offset(&self, addr: u16) -> usize {
+    let addr = addr as usize;
+    let bank = match (addr < ROM_BANK_BYTES, &self.kind) {
+      (true, &Some(MBCNum::N1)) if self.advanced_mode => self.ram_bank << 5,
+      (true, _) | (_, &None) => return addr,
+      (false, &Some(MBCNum::N1)) => self.ram_bank << 5 | self.rom_bank,
+      (false, _) => self.rom_bank,
+    };
+    // Real MBCs ignore the bank bits the ROM has no pins for; modulo gives the
+    // same result for power-of-two sizes and a sane one for the others.
+    (bank % self.rom_banks) * ROM_BANK_BYTES + addr % ROM_BANK_BYTES
+  }
+
+  /// Offset into external RAM that `off` (from 0xA000) currently maps to.
+  pub fn ram_offset(&self, off: usize) -> usize {
+    let bank = match self.kind {
+      Some(MBCNum::N1) if !self.advanced_mode => 0,
+      Some(MBCNum::N1) | Some(MBCNum::N3) | Some(MBCNum::N5) => self.ram_bank,
+      _ => 0,
+    };
+    bank * RAM_BANK_BYTES + off
+  }
+
+}
+
+/// Only 0x0A in the low nibble enables RAM; anything else disables it.
+fn enables_ram(value: u8) -> bool {
+  value & 0x0F == 0x0A
+}
+
+/// Bank registers that ignore a write of 0 and select bank 1 instead.
+fn nonzero(bank: u8) -> usize {
+  if bank == 0 { 1 } else { bank as usize }
+}
@@ -15,11 +15,913 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp;
+
+use super::bus::{AccessKind, Bus, WatchHit, WatchKind};
+use super::cart::{self, CartOptions, Cartridge, Component, MBCNum};
+use super::cpu::interrupt::Interrupt;
+use super::mbc::MBC;
+use super::model::Model;
+use super::rtc::RTC;
+use super::timer::Timer;
+
+const VRAM_BANK_BYTES: usize = 0x2000;
+const VRAM_BANKS_DMG: usize = 1;
+const VRAM_BANKS_CGB: usize = 2;
+const WRAM_BANK_BYTES: usize = 0x1000;
+const WRAM_BANKS_DMG: usize = 2;
+const WRAM_BANKS_CGB: usize = 8;
+const OAM_BYTES: usize = 0xA0;
+const IO_BYTES: usize = 0x80;
+const HRAM_BYTES: usize = 0x7F;
+
+/// Serial transfer data.
+const REG_SB: u16 = 0xFF01;
+/// Serial transfer control: bit 7 starts a transfer, bit 0 selects the internal clock.
+const REG_SC: u16 = 0xFF02;
+/// First and last of the timer registers: DIV, TIMA, TMA and TAC.
+const REG_DIV: u16 = 0xFF04;
+const REG_TAC: u16 = 0xFF07;
+/// Interrupt flags.
+const REG_IF: u16 = 0xFF0F;
+/// CGB speed switch: bit 7 is the current speed, bit 0 arms a switch on STOP.
+const REG_KEY1: u16 = 0xFF4D;
+/// CGB VRAM bank select.
+const REG_VBK: u16 = 0xFF4F;
+/// CGB WRAM bank select for 0xD000-0xDFFF.
+const REG_SVBK: u16 = 0xFF70;
+
+/// Value read back for unreadable or unmapped addresses.
+const OPEN_BUS: u8 = 0xFF;
+
+/// Value read back from 0xFEA0-0xFEFF. Hardware revisions disagree here; 0x00
+/// is what CGB returns and what DMG returns while OAM is blocked.
+const UNUSABLE_READ: u8 = 0x00;
+
+/// An address on the CPU bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address(pub u16);
+
+/// The memory map's regions, in address order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemRegion {
+  ROMBank0,
+  ROMBankN,
+  VRAM,
+  ExtRAM,
+  WRAM,
+  Echo,
+  OAM,
+  Unusable,
+  IO,
+  HRAM,
+  IE,
+}
+
+/// A single bus access, as reported to an access logger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+  pub addr: u16,
+  pub value: u8,
+  pub kind: AccessKind,
+}
+
+/// Everything the game can change in memory, captured by `MMU::save_state`.
+/// The ROM itself isn't included; the state only makes sense restored onto an
+/// MMU for the same cartridge and model.
+#[derive(Clone, Debug)]
+pub struct MMUState {
+  mbc: MBC,
+  ext_ram: Vec<u8>,
+  vram: Vec<u8>,
+  vram_bank: usize,
+  wram: Vec<u8>,
+  wram_bank: usize,
+  oam: Vec<u8>,
+  io: Vec<u8>,
+  timer: Timer,
+  hram: [u8; HRAM_BYTES],
+  ie: u8,
+  speed_armed: bool,
+  double_speed: bool,
+}
+
 pub struct MMU {
+  cart: Cartridge,
+  model: Model,
+  mbc: MBC,
+  ext_ram: Vec<u8>,
+  vram: Vec<u8>,
+  vram_bank: usize,
+  wram: Vec<u8>,
+  wram_bank: usize,
+  oam: Vec<u8>,
+  io: Vec<u8>,
+  timer: Timer,
+  hram: [u8; HRAM_BYTES],
+  ie: u8,
+  serial: Vec<u8>,
+  logger: Option<Box<dyn FnMut(Access)>>,
+  watchpoints: Vec<(u16, WatchKind)>,
+  watch_hit: Option<WatchHit>,
+  speed_armed: bool,
+  double_speed: bool,
+}
+
+impl Address {
+
+  pub fn region(&self) -> MemRegion {
+    match self.0 {
+      0x0000 ..= 0x3FFF => MemRegion::ROMBank0,
+      0x4000 ..= 0x7FFF => MemRegion::ROMBankN,
+      0x8000 ..= 0x9FFF => MemRegion::VRAM,
+      0xA000 ..= 0xBFFF => MemRegion::ExtRAM,
+      0xC000 ..= 0xDFFF => MemRegion::WRAM,
+      0xE000 ..= 0xFDFF => MemRegion::Echo,
+      0xFE00 ..= 0xFE9F => MemRegion::OAM,
+      0xFEA0 ..= 0xFEFF => MemRegion::Unusable,
+      0xFF00 ..= 0xFF7F => MemRegion::IO,
+      0xFF80 ..= 0xFFFE => MemRegion::HRAM,
+      _ => MemRegion::IE,
+    }
+  }
+
+  /// Distance from the first address of this address's region.
+  pub fn offset(&self) -> usize {
+    (self.0 - self.region().start()) as usize
+  }
+
+}
+
+impl MemRegion {
+
+  pub fn start(self) -> u16 {
+    match self {
+      MemRegion::ROMBank0 => 0x0000,
+      MemRegion::ROMBankN => 0x4000,
+      MemRegion::VRAM => 0x8000,
+      MemRegion::ExtRAM => 0xA000,
+      MemRegion::WRAM => 0xC000,
+      MemRegion::Echo => 0xE000,
+      MemRegion::OAM => 0xFE00,
+      MemRegion::Unusable => 0xFEA0,
+      MemRegion::IO => 0xFF00,
+      MemRegion::HRAM => 0xFF80,
+      MemRegion::IE => 0xFFFF,
+    }
+  }
+
+}
+
+impl MMU {
+
+  /// Maps `cart` on the model it was made for.
+  pub fn new(cart: Cartridge) -> MMU {
+    let model = if cart.is_cgb() { Model::CGB } else { Model::DMG };
+    MMU::with_model(cart, model)
+  }
+
+  pub fn with_model(cart: Cartridge, model: Model) -> MMU {
+    MMU::with_options(cart, model, &CartOptions::new())
+  }
+
+  pub fn with_options(cart: Cartridge, model: Model, options: &CartOptions) -> MMU {
+    let (vram_banks, wram_banks) = match model {
+      Model::CGB => (VRAM_BANKS_CGB, WRAM_BANKS_CGB),
+      _ => (VRAM_BANKS_DMG, WRAM_BANKS_DMG),
+    };
+
+    let (mbc, ram_bytes) = match options.forced_mbc() {
+      Some(&MBCNum::N2) => (Some(MBCNum::N2), cart::MBC2_RAM_BYTES),
+      Some(forced) => (Some(forced.clone()), cart.save_ram_size_bytes()),
+      None => (cart.mbc(), cart.save_ram_size_bytes()),
+    };
+    let mut mbc = MBC::new(mbc, cart.effective_rom_size());
+    if cart.has_component(Component::RTC) {
+      mbc = mbc.with_rtc(RTC::new());
+    }
+
+    MMU {
+      cart,
+      model,
+      mbc,
+      ext_ram: vec![0; ram_bytes],
+      vram: vec![0; vram_banks * VRAM_BANK_BYTES],
+      vram_bank: 0,
+      wram: vec![0; wram_banks * WRAM_BANK_BYTES],
+      wram_bank: 1,
+      oam: vec![0; OAM_BYTES],
+      io: vec![0; IO_BYTES],
+      timer: Timer::new(),
+      hram: [0; HRAM_BYTES],
+      ie: 0,
+      serial: Vec::new(),
+      logger: None,
+      watchpoints: Vec::new(),
+      watch_hit: None,
+      speed_armed: false,
+      double_speed: false,
+    }
+  }
+
+  pub fn cart(&self) -> &Cartridge {
+    &self.cart
+  }
+
+  /// Captures RAM, I/O registers and the mapper's bank registers. Debugging
+  /// hooks and serial output are left out.
+  pub fn save_state(&self) -> MMUState {
+    MMUState {
+      mbc: self.mbc.clone(),
+      ext_ram: self.ext_ram.clone(),
+      vram: self.vram.clone(),
+      vram_bank: self.vram_bank,
+      wram: self.wram.clone(),
+      wram_bank: self.wram_bank,
+      oam: self.oam.clone(),
+      io: self.io.clone(),
+      timer: self.timer.clone(),
+      hram: self.hram,
+      ie: self.ie,
+      speed_armed: self.speed_armed,
+      double_speed: self.double_speed,
+    }
+  }
+
+  /// Puts back what `save_state` captured.
+  pub fn restore_state(&mut self, state: MMUState) {
+    self.mbc = state.mbc;
+    self.ext_ram = state.ext_ram;
+    self.vram = state.vram;
+    self.vram_bank = state.vram_bank;
+    self.wram = state.wram;
+    self.wram_bank = state.wram_bank;
+    self.oam = state.oam;
+    self.io = state.io;
+    self.timer = state.timer;
+    self.hram = state.hram;
+    self.ie = state.ie;
+    self.speed_armed = state.speed_armed;
+    self.double_speed = state.double_speed;
+  }
+
+  /// External RAM as a battery would keep it.
+  pub fn save_ram(&self) -> &[u8] {
+    &self.ext_ram
+  }
+
+  /// Restores external RAM saved by `save_ram`. Extra bytes are ignored.
+  pub fn load_save_ram(&mut self, bytes: &[u8]) {
+    let n = cmp::min(bytes.len(), self.ext_ram.len());
+    self.ext_ram[.. n].copy_from_slice(&bytes[.. n]);
+  }
+
+  /// The MBC3 clock, which should be saved along with `save_ram`.
+  pub fn rtc(&self) -> Option<&RTC> {
+    self.mbc.rtc()
+  }
+
+  /// Replaces the MBC3 clock, e.g. with one restored by `RTC::from_bytes`.
+  /// Does nothing on cartridges without one.
+  pub fn load_rtc(&mut self, rtc: RTC) {
+    if let Some(current) = self.mbc.rtc_mut() {
+      *current = rtc;
+    }
+  }
+
+  pub fn model(&self) -> Model {
+    self.model
+  }
+
+  pub fn timer(&self) -> &Timer {
+    &self.timer
+  }
+
+  pub fn is_double_speed(&self) -> bool {
+    self.double_speed
+  }
+
+  /// Installs a callback invoked after every bus read and write.
+  pub fn set_access_logger(&mut self, logger: Box<dyn FnMut(Access)>) {
+    self.logger = Some(logger);
+  }
+
+  pub fn clear_access_logger(&mut self) {
+    self.logger = None;
+  }
+
+  pub fn add_watchpoint(&mut self, addr: u16, kind: WatchKind) {
+    self.watchpoints.push((addr, kind));
+  }
+
+  pub fn remove_watchpoint(&mut self, addr: u16) {
+    self.watchpoints.retain(|&(a, _)| a != addr);
+  }
+
+  fn check_watch(&mut self, addr: u16, value: u8, kind: WatchKind) {
+    if self.watch_hit.is_some() {
+      return;
+    }
+    let hit = self.watchpoints.iter().any(|&(a, k)| {
+      a == addr && (k == kind || k == WatchKind::ReadWrite)
+    });
+    if hit {
+      self.watch_hit = Some(WatchHit { addr, value, kind });
+    }
+  }
+
+  /// Every byte shifted out over the serial port so far.
+  pub fn serial_output(&self) -> &[u8] {
+    &self.serial
+  }
+
+  /// Completes an internally clocked transfer at once. With no link partner the
+  /// byte shifted in is 0xFF.
+  fn write_sc(&mut self, value: u8) {
+    if value & 0x81 != 0x81 {
+      self.io[(REG_SC - 0xFF00) as usize] = value;
+      return;
+    }
+
+    let sb = (REG_SB - 0xFF00) as usize;
+    self.serial.push(self.io[sb]);
+    self.io[sb] = 0xFF;
+    self.io[(REG_SC - 0xFF00) as usize] = value & 0x7F;
+    self.request_interrupt(Interrupt::Serial);
+  }
+
+  fn request_interrupt(&mut self, int: Interrupt) {
+    self.io[(REG_IF - 0xFF00) as usize] |= 1 << int.bit();
+  }
+
+  /// Index into `wram` for an offset from 0xC000. Bank 0 is fixed at 0xC000;
+  /// 0xD000 maps whichever bank SVBK selects (always bank 1 on DMG).
+  fn wram_index(&self, off: usize) -> usize {
+    if off < WRAM_BANK_BYTES {
+      off
+    } else {
+      self.wram_bank * WRAM_BANK_BYTES + off - WRAM_BANK_BYTES
+    }
+  }
+
+  fn read_ext_ram(&self, off: usize) -> u8 {
+    if !self.mbc.ram_enabled() {
+      return OPEN_BUS;
+    }
+    if let Some(value) = self.mbc.read_rtc() {
+      return value;
+    }
+    match self.mbc.kind() {
+      // Only the low nibble is stored; the upper one floats high. The 512 cells
+      // repeat through the whole region.
+      Some(&MBCNum::N2) => 0xF0 | self.ext_ram[off % cart::MBC2_RAM_BYTES],
+      _ => self.ext_ram.get(self.mbc.ram_offset(off)).cloned().unwrap_or(OPEN_BUS),
+    }
+  }
+
+  fn write_ext_ram(&mut self, off: usize, value: u8) {
+    if !self.mbc.ram_enabled() || self.mbc.write_rtc(value) {
+      return;
+    }
+    match self.mbc.kind() {
+      Some(&MBCNum::N2) => self.ext_ram[off % cart::MBC2_RAM_BYTES] = value & 0x0F,
+      _ => if let Some(cell) = self.ext_ram.get_mut(self.mbc.ram_offset(off)) {
+        *cell = value;
+      },
+    }
+  }
+
+  fn read_key1(&self) -> u8 {
+    let speed = if self.double_speed { 0x80 } else { 0x00 };
+    let armed = if self.speed_armed { 0x01 } else { 0x00 };
+    speed | 0x7E | armed
+  }
 
 }
 
 impl MMU {
 
+  fn read(&mut self, addr: u16) -> u8 {
+    let addr = Address(addr);
+    let off = addr.offset();
+    match addr.region() {
+      MemRegion::ROMBank0 | MemRegion::ROMBankN =>
+        self.cart.read_rom(self.mbc.rom_offset(addr.0)).unwrap_or(OPEN_BUS),
+      MemRegion::VRAM => self.vram[self.vram_bank * VRAM_BANK_BYTES + off],
+      MemRegion::ExtRAM => self.read_ext_ram(off),
+      // Echo RAM mirrors the first 0x1E00 bytes of WRAM.
+      MemRegion::WRAM | MemRegion::Echo => self.wram[self.wram_index(off)],
+      MemRegion::OAM => self.oam[off],
+      MemRegion::Unusable => UNUSABLE_READ,
+      MemRegion::IO if addr.0 >= REG_DIV && addr.0 <= REG_TAC => self.timer.read(addr.0),
+      MemRegion::IO if addr.0 == REG_KEY1 && self.model == Model::CGB => self.read_key1(),
+      MemRegion::IO if addr.0 == REG_VBK && self.model == Model::CGB => 0xFE | self.vram_bank as u8,
+      MemRegion::IO if addr.0 == REG_SVBK && self.model == Model::CGB => 0xF8 | self.wram_bank as u8,
+      MemRegion::IO => self.io[off],
+      MemRegion::HRAM => self.hram[off],
+      MemRegion::IE => self.ie,
+    }
+  }
+
+  fn write(&mut self, addr: u16, value: u8) {
+    let addr = Address(addr);
+    let off = addr.offset();
+    match addr.region() {
+      MemRegion::ROMBank0 | MemRegion::ROMBankN => self.mbc.write(addr.0, value),
+      MemRegion::VRAM => self.vram[self.vram_bank * VRAM_BANK_BYTES + off] = value,
+      MemRegion::ExtRAM => self.write_ext_ram(off, value),
+      MemRegion::WRAM | MemRegion::Echo => {
+        let i = self.wram_index(off);
+        self.wram[i] = value;
+      },
+      MemRegion::OAM => self.oam[off] = value,
+      MemRegion::Unusable => {},
+      MemRegion::IO if addr.0 >= REG_DIV && addr.0 <= REG_TAC => if self.timer.write(addr.0, value) {
+        self.request_interrupt(Interrupt::Timer);
+      },
+      // Only the arm bit is writable; the speed bit changes on STOP.
+      MemRegion::IO if addr.0 == REG_KEY1 && self.model == Model::CGB =>
+        self.speed_armed = value & 0x01 != 0,
+      MemRegion::IO if addr.0 == REG_VBK && self.model == Model::CGB =>
+        self.vram_bank = (value & 0x01) as usize,
+      // Selecting bank 0 selects bank 1 instead.
+      MemRegion::IO if addr.0 == REG_SVBK && self.model == Model::CGB =>
+        self.wram_bank = match value & 0x07 { 0 => 1, n => n as usize },
+      MemRegion::IO if addr.0 == REG_SC => self.write_sc(value),
+      MemRegion::IO => self.io[off] = value,
+      MemRegion::HRAM => self.hram[off] = value,
+      MemRegion::IE => self.ie = value,
+    }
+  }
+
+}
+
+impl Bus for MMU {
+
+  fn read_u8(&mut self, addr: u16) -> u8 {
+    let value = self.read(addr);
+    if let Some(ref mut log) = self.logger {
+      log(Access { addr, value, kind: AccessKind::Read });
+    }
+    if !self.watchpoints.is_empty() {
+      self.check_watch(addr, value, WatchKind::Read);
+    }
+    value
+  }
+
+  fn write_u8(&mut self, addr: u16, value: u8) {
+    self.write(addr, value);
+    if let Some(ref mut log) = self.logger {
+      log(Access { addr, value, kind: AccessKind::Write });
+    }
+    if !self.watchpoints.is_empty() {
+      self.check_watch(addr, value, WatchKind::Write);
+    }
+  }
+
+  fn speed_switch_armed(&self) -> bool {
+    self.speed_armed
+  }
+
+  fn switch_speed(&mut self) {
+    self.speed_armed = false;
+    self.double_speed = !self.double_speed;
+  }
+
+  fn take_watch_hit(&mut self) -> Option<WatchHit> {
+    self.watch_hit.take()
+  }
+
+  fn tick(&mut self, cycles: usize) {
+    // The RTC's crystal doesn't speed up with the CPU.
+    self.mbc.tick(if self.double_speed { cycles / 2 } else { cycles });
+    if self.timer.tick(cycles) {
+      self.request_interrupt(Interrupt::Timer);
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::rc::Rc;
+  use core::cell::RefCell;
+
+  use hw::bus::{AccessKind, Bus, WatchHit, WatchKind};
+  use hw::cart::tests::{fix_checksums, rom};
+  use hw::cpu::{Processor, StepErr};
+  use hw::cpu::clock::{Clock, Frequency};
+  use hw::cpu::register::Register;
+  use super::*;
+
+  /// A 32KB cartridge of type `kind` with `program` at the entry point.
+  fn cart(kind: u8, program: &[u8]) -> Cartridge {
+    let mut bytes = rom("MMU", kind, 0x00, 0x00);
+    bytes[0x100 .. 0x100 + program.len()].copy_from_slice(program);
+    fix_checksums(&mut bytes);
+    Cartridge::new(bytes).unwrap()
+  }
+
+  fn cpu_at_entry() -> Processor {
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    cpu
+  }
+
+  #[test]
+  fn stop_switches_speed_once_key1_is_armed() {
+    let mut mmu = MMU::with_model(cart(0x00, &[0x10, 0x00]), Model::CGB);
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::CGB);
+
+    assert_eq!(mmu.read_u8(REG_KEY1), 0x7E);
+    mmu.write_u8(REG_KEY1, 0x01);
+    assert_eq!(mmu.read_u8(REG_KEY1), 0x7F);
+    cpu.step(&mut mmu, &mut clock).unwrap();
+
+    assert_eq!(clock.frequency(), Frequency::Double);
+    assert!(mmu.is_double_speed());
+    assert!(!cpu.is_stopped());
+    assert_eq!(mmu.read_u8(REG_KEY1), 0xFE);
+  }
+
+  #[test]
+  fn stop_without_key1_armed_just_stops() {
+    let mut mmu = MMU::with_model(cart(0x00, &[0x10, 0x00]), Model::CGB);
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::CGB);
+
+    cpu.step(&mut mmu, &mut clock).unwrap();
+
+    assert_eq!(clock.frequency(), Frequency::Single);
+    assert!(!mmu.is_double_speed());
+    assert!(cpu.is_stopped());
+  }
+
+  #[test]
+  fn dmg_has_no_speed_switch() {
+    let mut mmu = MMU::with_model(cart(0x00, &[0x10, 0x00]), Model::DMG);
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::DMG);
+
+    mmu.write_u8(REG_KEY1, 0x01);
+    cpu.step(&mut mmu, &mut clock).unwrap();
+
+    assert_eq!(clock.frequency(), Frequency::Single);
+    assert!(!mmu.is_double_speed());
+    assert!(cpu.is_stopped());
+  }
+
+  #[test]
+  fn addresses_classify_at_region_boundaries() {
+    let expected = [
+      (0x0000, MemRegion::ROMBank0),
+      (0x3FFF, MemRegion::ROMBank0),
+      (0x4000, MemRegion::ROMBankN),
+      (0x7FFF, MemRegion::ROMBankN),
+      (0x8000, MemRegion::VRAM),
+      (0x9FFF, MemRegion::VRAM),
+      (0xA000, MemRegion::ExtRAM),
+      (0xC000, MemRegion::WRAM),
+      (0xDFFF, MemRegion::WRAM),
+      (0xE000, MemRegion::Echo),
+      (0xFDFF, MemRegion::Echo),
+      (0xFE00, MemRegion::OAM),
+      (0xFE9F, MemRegion::OAM),
+      (0xFEA0, MemRegion::Unusable),
+      (0xFEFF, MemRegion::Unusable),
+      (0xFF00, MemRegion::IO),
+      (0xFF7F, MemRegion::IO),
+      (0xFF80, MemRegion::HRAM),
+      (0xFFFE, MemRegion::HRAM),
+      (0xFFFF, MemRegion::IE),
+    ];
+    for &(addr, region) in expected.iter() {
+      assert_eq!(Address(addr).region(), region, "0x{:04X}", addr);
+    }
+    assert_eq!(Address(0xFEA0).offset(), 0);
+    assert_eq!(Address(0x7FFF).offset(), 0x3FFF);
+  }
+
+  #[test]
+  fn echo_ram_mirrors_wram() {
+    let mut mmu = MMU::new(cart(0x00, &[]));
+
+    mmu.write_u8(0xC010, 0x42);
+    assert_eq!(mmu.read_u8(0xE010), 0x42);
+    mmu.write_u8(0xFDFF, 0x24);
+    assert_eq!(mmu.read_u8(0xDDFF), 0x24);
+  }
+
+  #[test]
+  fn unusable_region_reads_a_constant_and_ignores_writes() {
+    let mut mmu = MMU::new(cart(0x00, &[]));
+
+    assert_eq!(mmu.read_u8(0xFEA0), UNUSABLE_READ);
+    mmu.write_u8(0xFEFF, 0x42);
+    assert_eq!(mmu.read_u8(0xFEFF), 0x00);
+  }
+
+  #[test]
+  fn hram_is_separate_from_io() {
+    let mut mmu = MMU::new(cart(0x00, &[]));
+
+    mmu.write_u8(0xFF80, 0x12);
+    mmu.write_u8(0xFF40, 0x91);
+    mmu.write_u8(0xFFFE, 0x34);
+    assert_eq!(mmu.read_u8(0xFF80), 0x12);
+    assert_eq!(mmu.read_u8(0xFF40), 0x91);
+    assert_eq!(mmu.read_u8(0xFFFE), 0x34);
+    assert_eq!(mmu.read_u8(0xFFFF), 0x00);
+  }
+
+  #[test]
+  fn svbk_switches_the_upper_wram_bank() {
+    let mut mmu = MMU::with_model(cart(0x00, &[]), Model::CGB);
+
+    mmu.write_u8(0xC000, 0xC0);
+    for bank in 1 .. 8 {
+      mmu.write_u8(REG_SVBK, bank);
+      mmu.write_u8(0xD000, bank * 0x11);
+    }
+    for bank in 1 .. 8 {
+      mmu.write_u8(REG_SVBK, bank);
+      assert_eq!(mmu.read_u8(0xD000), bank * 0x11);
+      assert_eq!(mmu.read_u8(0xC000), 0xC0);
+    }
+
+    // Bank 0 selects bank 1.
+    mmu.write_u8(REG_SVBK, 0);
+    assert_eq!(mmu.read_u8(0xD000), 0x11);
+    assert_eq!(mmu.read_u8(REG_SVBK), 0xF9);
+  }
+
+  #[test]
+  fn vbk_switches_vram_banks() {
+    let mut mmu = MMU::with_model(cart(0x00, &[]), Model::CGB);
+
+    mmu.write_u8(REG_VBK, 0);
+    mmu.write_u8(0x8000, 0xAA);
+    mmu.write_u8(REG_VBK, 1);
+    mmu.write_u8(0x8000, 0xBB);
+
+    assert_eq!(mmu.read_u8(REG_VBK), 0xFF);
+    assert_eq!(mmu.read_u8(0x8000), 0xBB);
+    mmu.write_u8(REG_VBK, 0);
+    assert_eq!(mmu.read_u8(0x8000), 0xAA);
+  }
+
+  #[test]
+  fn access_logger_sees_reads_and_writes() {
+    let mut mmu = MMU::new(cart(0x00, &[]));
+    let log = Rc::new(RefCell::new(Vec::new()));
+    let sink = log.clone();
+    mmu.set_access_logger(Box::new(move |access| sink.borrow_mut().push(access)));
+
+    mmu.write_u8(0xC123, 0x5A);
+    assert_eq!(mmu.read_u8(0xC123), 0x5A);
+    mmu.clear_access_logger();
+    mmu.read_u8(0xC123);
+
+    assert_eq!(*log.borrow(), vec![
+      Access { addr: 0xC123, value: 0x5A, kind: AccessKind::Write },
+      Access { addr: 0xC123, value: 0x5A, kind: AccessKind::Read },
+    ]);
+  }
+
+  #[test]
+  fn watched_writes_stop_the_step() {
+    // LDH (0x81),A; LDH (0x80),A
+    let mut mmu = MMU::new(cart(0x00, &[0xE0, 0x81, 0xE0, 0x80]));
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::DMG);
+    mmu.add_watchpoint(0xFF80, WatchKind::Write);
+
+    cpu.step(&mut mmu, &mut clock).unwrap();
+    match cpu.step(&mut mmu, &mut clock) {
+      Err(StepErr::Watchpoint(hit)) => {
+        assert_eq!(hit, WatchHit { addr: 0xFF80, value: 0x00, kind: WatchKind::Write });
+      },
+      x => panic!("expected a watchpoint hit, got {:?}", x),
+    }
+    assert_eq!(cpu.regs().pc().get(), 0x0104);
+  }
+
+  #[test]
+  fn failed_step_drops_its_watch_hit() {
+    let mut mmu = MMU::new(cart(0x00, &[0xD3]));
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::DMG);
+    mmu.add_watchpoint(0x0100, WatchKind::Read);
+
+    match cpu.step(&mut mmu, &mut clock) {
+      Err(StepErr::Illegal(0xD3)) => {},
+      x => panic!("expected an illegal opcode, got {:?}", x),
+    }
+    assert_eq!(mmu.take_watch_hit(), None);
+  }
+
+  #[test]
+  fn mbc2_ram_keeps_only_the_low_nibble() {
+    let mut mmu = MMU::new(cart(0x05, &[]));
+    mmu.write_u8(0x0000, 0x0A);
+
+    mmu.write_u8(0xA000, 0x35);
+    assert_eq!(mmu.read_u8(0xA000), 0xF5);
+    mmu.write_u8(0xA001, 0xFF);
+    assert_eq!(mmu.read_u8(0xA001), 0xFF);
+    mmu.write_u8(0xA002, 0x00);
+    assert_eq!(mmu.read_u8(0xA002), 0xF0);
+
+    // The 512 cells repeat through the region.
+    assert_eq!(mmu.read_u8(0xA200), 0xF5);
+    assert_eq!(mmu.read_u8(0xBE00), 0xF5);
+  }
+
+  #[test]
+  fn forced_mbc1_switches_banks_on_a_plain_rom_header() {
+    let mut bytes = rom("MISLABEL", 0x00, 0x01, 0x00);
+    for bank in 1..4 {
+      bytes[bank * 0x4000] = bank as u8;
+    }
+    fix_checksums(&mut bytes);
+
+    let mut plain = MMU::new(Cartridge::new(bytes.clone()).unwrap());
+    plain.write_u8(0x2000, 0x02);
+    assert_eq!(plain.read_u8(0x4000), 0x01);
+
+    let options = CartOptions::new().force_mbc(Some(MBCNum::N1));
+    let mut mmu = MMU::with_options(Cartridge::new(bytes).unwrap(), Model::DMG, &options);
+    assert_eq!(mmu.read_u8(0x4000), 0x01);
+    mmu.write_u8(0x2000, 0x02);
+    assert_eq!(mmu.read_u8(0x4000), 0x02);
+    mmu.write_u8(0x2000, 0x03);
+    assert_eq!(mmu.read_u8(0x4000), 0x03);
+  }
+
+  #[test]
+  fn banks_past_a_short_file_read_as_ff() {
+    let mut bytes = rom("SHORT", 0x01, 0x01, 0x00);
+    bytes.truncate(0x8000);
+    bytes[0x4000] = 0x11;
+    fix_checksums(&mut bytes);
+    let cart = Cartridge::new(bytes).unwrap();
+    assert_eq!(cart.effective_rom_size(), 0x10000);
+
+    let mut mmu = MMU::new(cart);
+    assert_eq!(mmu.read_u8(0x4000), 0x11);
+    mmu.write_u8(0x2000, 0x02);
+    assert_eq!(mmu.read_u8(0x4000), 0xFF);
+    mmu.write_u8(0x2000, 0x03);
+    assert_eq!(mmu.read_u8(0x7FFF), 0xFF);
+  }
+
+  #[test]
+  fn out_of_range_banks_wrap() {
+    let mut bytes = rom("WRAP", 0x19, 0x03, 0x00);
+    for bank in 0..16 {
+      bytes[bank * 0x4000 + 0x10] = 0xB0 | bank as u8;
+    }
+    fix_checksums(&mut bytes);
+    let mut mmu = MMU::new(Cartridge::new(bytes).unwrap());
+
+    mmu.write_u8(0x2000, 0x20);
+    assert_eq!(mmu.read_u8(0x4010), 0xB0);
+    mmu.write_u8(0x2000, 0x13);
+    assert_eq!(mmu.read_u8(0x4010), 0xB3);
+    mmu.write_u8(0x3000, 0x01);
+    assert_eq!(mmu.read_u8(0x4010), 0xB3);
+  }
+
+  #[test]
+  fn external_ram_reads_open_bus_until_enabled() {
+    let mut mmu = MMU::new(Cartridge::new(rom("GATE", 0x03, 0x01, 0x02)).unwrap());
+
+    mmu.write_u8(0xA000, 0x42);
+    assert_eq!(mmu.read_u8(0xA000), OPEN_BUS);
+
+    mmu.write_u8(0x1FFF, 0x0A);
+    assert_eq!(mmu.read_u8(0xA000), 0x00);
+    mmu.write_u8(0xA000, 0x42);
+    assert_eq!(mmu.read_u8(0xA000), 0x42);
+
+    // Anything but 0x0A in the low nibble disables it again; the data stays.
+    mmu.write_u8(0x0000, 0x00);
+    assert_eq!(mmu.read_u8(0xA000), OPEN_BUS);
+    mmu.write_u8(0x0000, 0x0A);
+    assert_eq!(mmu.read_u8(0xA000), 0x42);
+  }
+
+  #[test]
+  fn restore_state_brings_back_banks_and_memory() {
+    let mut bytes = rom("STATE", 0x1B, 0x02, 0x03);
+    for bank in 0..8 {
+      bytes[bank * 0x4000 + 0x10] = 0xB0 | bank as u8;
+    }
+    fix_checksums(&mut bytes);
+    let mut mmu = MMU::with_model(Cartridge::new(bytes).unwrap(), Model::CGB);
+
+    let set = |mmu: &mut MMU, n: u8| {
+      mmu.write_u8(0x0000, 0x0A);
+      mmu.write_u8(0x2000, n);
+      mmu.write_u8(0x4000, n);
+      mmu.write_u8(0xA000, n);
+      mmu.write_u8(REG_SVBK, n);
+      mmu.write_u8(0xD000, n);
+      mmu.write_u8(REG_VBK, n & 1);
+      mmu.write_u8(0x8000, n);
+      mmu.write_u8(0xFF80, n);
+      mmu.write_u8(0xFFFF, n);
+    };
+    let check = |mmu: &mut MMU, n: u8| {
+      assert_eq!(mmu.read_u8(0x4010), 0xB0 | n);
+      assert_eq!(mmu.read_u8(0xA000), n);
+      assert_eq!(mmu.read_u8(0xD000), n);
+      assert_eq!(mmu.read_u8(0x8000), n);
+      assert_eq!(mmu.read_u8(0xFF80), n);
+      assert_eq!(mmu.read_u8(0xFFFF) & 0x1F, n);
+    };
+
+    set(&mut mmu, 3);
+    let state = mmu.save_state();
+    set(&mut mmu, 2);
+    check(&mut mmu, 2);
+
+    mmu.restore_state(state);
+    check(&mut mmu, 3);
+    // Writes made after the save are gone from the other banks too.
+    mmu.write_u8(0x4000, 2);
+    assert_eq!(mmu.read_u8(0xA000), 0x00);
+  }
+
+  #[test]
+  fn rtc_latches_and_carries_out_of_the_day_counter() {
+    let mut mmu = MMU::new(Cartridge::new(rom("GOLD", 0x10, 0x01, 0x03)).unwrap());
+    mmu.write_u8(0x0000, 0x0A);
+    let select = |mmu: &mut MMU, reg: u8| mmu.write_u8(0x4000, 0x08 + reg);
+    let latch = |mmu: &mut MMU| {
+      mmu.write_u8(0x6000, 0x00);
+      mmu.write_u8(0x6000, 0x01);
+    };
+
+    // Day 511, 23:59:59: one second from the day counter overflowing.
+    for &(reg, value) in [(0, 59), (1, 59), (2, 23), (3, 0xFF), (4, 0x01)].iter() {
+      select(&mut mmu, reg);
+      mmu.write_u8(0xA000, value);
+    }
+    select(&mut mmu, 0);
+    assert_eq!(mmu.read_u8(0xA000), 0);
+    latch(&mut mmu);
+    assert_eq!(mmu.read_u8(0xA000), 59);
+
+    mmu.tick(Frequency::Single.hz() as usize);
+    assert_eq!(mmu.read_u8(0xA000), 59);
+    latch(&mut mmu);
+    let regs: Vec<u8> = (0..5).map(|reg| {
+      select(&mut mmu, reg);
+      mmu.read_u8(0xA000)
+    }).collect();
+    assert_eq!(regs, vec![0, 0, 0, 0x00, 0x80]);
+
+    // RAM banks are still reachable alongside the clock.
+    mmu.write_u8(0x4000, 0x01);
+    mmu.write_u8(0xA000, 0x42);
+    assert_eq!(mmu.read_u8(0xA000), 0x42);
+    assert_eq!(mmu.rtc().unwrap().to_bytes()[4], 0x80);
+  }
+
+  #[test]
+  fn rtc_keeps_real_time_in_double_speed() {
+    // STOP, with KEY1 armed
+    let mut mmu = MMU::with_model(cart(0x0F, &[0x10, 0x00]), Model::CGB);
+    let mut cpu = cpu_at_entry();
+    let mut clock = Clock::for_model(Model::CGB);
+    mmu.write_u8(REG_KEY1, 0x01);
+    cpu.step(&mut mmu, &mut clock).unwrap();
+    assert!(mmu.is_double_speed());
+
+    mmu.write_u8(0x0000, 0x0A);
+    mmu.write_u8(0x4000, 0x08);
+    let seconds = |mmu: &mut MMU| {
+      mmu.write_u8(0x6000, 0x00);
+      mmu.write_u8(0x6000, 0x01);
+      mmu.read_u8(0xA000)
+    };
+    let before = seconds(&mut mmu);
+
+    // One second takes twice the CPU cycles at double speed.
+    mmu.tick(2 * Frequency::Single.hz() as usize);
+    assert_eq!(seconds(&mut mmu), before + 1);
+  }
+
+  #[test]
+  fn sram_without_a_declared_size_gets_the_default() {
+    let cart = Cartridge::new(rom("MMM01", 0x0C, 0x00, 0x00)).unwrap();
+    assert_eq!(cart.save_ram_size_bytes(), cart::SRAM_DEFAULT_BYTES);
 
+    let mmu = MMU::new(cart);
+    assert_eq!(mmu.save_ram().len(), cart::SRAM_DEFAULT_BYTES);
+  }
 }
@@ -15,6 +15,13 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+pub mod bus;
 pub mod cart;
+pub mod mbc;
 pub mod cpu;
 pub mod mmu;
+pub mod model;
+pub mod replay;
+pub mod rtc;
+pub mod test_rom;
+pub mod timer;
@@ -0,0 +1,252 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use alloc::vec::Vec;
+
+use super::bus::{AccessKind, Bus, WatchHit};
+
+/// One memory access, stamped with the T-cycle of the instruction that made it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusAccess {
+  pub time: usize,
+  pub addr: u16,
+  pub value: u8,
+  pub kind: AccessKind,
+}
+
+/// Passes everything through to `B`, logging each read and write.
+pub struct RecordingBus<B: Bus> {
+  inner: B,
+  time: usize,
+  log: Vec<BusAccess>,
+}
+
+/// Answers reads from a recorded log instead of real hardware, so a session
+/// replays exactly. Writes are checked against the log and dropped.
+pub struct ReplayBus {
+  log: Vec<BusAccess>,
+  next: usize,
+  /// Index of the first access that didn't match the log.
+  diverged: Option<usize>,
+}
+
+impl<B: Bus> RecordingBus<B> {
+
+  pub fn new(inner: B) -> RecordingBus<B> {
+    RecordingBus {
+      inner,
+      time: 0,
+      log: Vec::new(),
+    }
+  }
+
+  pub fn log(&self) -> &[BusAccess] {
+    &self.log
+  }
+
+  pub fn inner(&self) -> &B {
+    &self.inner
+  }
+
+  /// Stops recording, returning the wrapped bus and the log.
+  pub fn into_parts(self) -> (B, Vec<BusAccess>) {
+    (self.inner, self.log)
+  }
+
+  fn record(&mut self, addr: u16, value: u8, kind: AccessKind) {
+    self.log.push(BusAccess { time: self.time, addr, value, kind });
+  }
+
+}
+
+impl<B: Bus> Bus for RecordingBus<B> {
+  fn read_u8(&mut self, addr: u16) -> u8 {
+    let value = self.inner.read_u8(addr);
+    self.record(addr, value, AccessKind::Read);
+    value
+  }
+
+  fn write_u8(&mut self, addr: u16, value: u8) {
+    self.inner.write_u8(addr, value);
+    self.record(addr, value, AccessKind::Write);
+  }
+
+  fn speed_switch_armed(&self) -> bool {
+    self.inner.speed_switch_armed()
+  }
+
+  fn switch_speed(&mut self) {
+    self.inner.switch_speed()
+  }
+
+  fn take_watch_hit(&mut self) -> Option<WatchHit> {
+    self.inner.take_watch_hit()
+  }
+
+  fn tick(&mut self, cycles: usize) {
+    self.time += cycles;
+    self.inner.tick(cycles);
+  }
+}
+
+impl ReplayBus {
+
+  pub fn new(log: Vec<BusAccess>) -> ReplayBus {
+    ReplayBus {
+      log,
+      next: 0,
+      diverged: None,
+    }
+  }
+
+  /// Index into the log of the first access that differed from the recording,
+  /// if any. After that, reads return 0xFF.
+  pub fn diverged(&self) -> Option<usize> {
+    self.diverged
+  }
+
+  /// Whether every recorded access has been replayed.
+  pub fn is_finished(&self) -> bool {
+    self.next == self.log.len()
+  }
+
+  /// Consumes the next access, checking it is to `addr` and is a read, or a
+  /// write of `written` if that is given. Returns the recorded value.
+  fn replay(&mut self, addr: u16, written: Option<u8>) -> Option<u8> {
+    if self.diverged.is_some() {
+      return None;
+    }
+    let value = match self.log.get(self.next) {
+      Some(access) if access.addr == addr => match (access.kind, written) {
+        (AccessKind::Read, None) => Some(access.value),
+        (AccessKind::Write, Some(v)) if v == access.value => Some(v),
+        _ => None,
+      },
+      _ => None,
+    };
+    match value {
+      Some(_) => self.next += 1,
+      None => self.diverged = Some(self.next),
+    }
+    value
+  }
+
+}
+
+impl Bus for ReplayBus {
+  fn read_u8(&mut self, addr: u16) -> u8 {
+    self.replay(addr, None).unwrap_or(0xFF)
+  }
+
+  fn write_u8(&mut self, addr: u16, value: u8) {
+    self.replay(addr, Some(value));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hw::bus::tests::FlatBus;
+  use hw::cpu::Processor;
+  use hw::cpu::clock::{Clock, Frequency};
+  use hw::cpu::register::Register;
+  use super::*;
+
+  // LD A,0x42; LD (HL+),A; INC (HL); CALL 0x0200 ... 0x0200: LDH (0x80),A; RET
+  const STEPS: usize = 6;
+
+  fn cpu() -> Processor {
+    let mut cpu = Processor::new();
+    cpu.regs_mut().pc_mut().set(0x0100);
+    cpu.regs_mut().sp_mut().set(0xFFFE);
+    cpu.regs_mut().hl_mut().set(0xC000);
+    cpu
+  }
+
+  fn run<B: Bus>(cpu: &mut Processor, bus: &mut B) {
+    let mut clock = Clock::new(Frequency::Single);
+    for _ in 0..STEPS {
+      cpu.step(bus, &mut clock).unwrap();
+    }
+  }
+
+  fn recording() -> (Processor, Vec<BusAccess>) {
+    let mut flat = FlatBus::with_program(0x0100, &[0x3E, 0x42, 0x22, 0x34, 0xCD, 0x00, 0x02]);
+    flat.mem[0x0200 .. 0x0203].copy_from_slice(&[0xE0, 0x80, 0xC9]);
+    let mut bus = RecordingBus::new(flat);
+    let mut cpu = cpu();
+    run(&mut cpu, &mut bus);
+
+    let (flat, log) = bus.into_parts();
+    assert_eq!(flat.mem[0xC000], 0x42);
+    assert_eq!(flat.mem[0xC001], 0x01);
+    assert_eq!(flat.mem[0xFF80], 0x42);
+    (cpu, log)
+  }
+
+  #[test]
+  fn replay_reproduces_the_recorded_run() {
+    let (recorded, log) = recording();
+    assert!(log.iter().any(|a| a.kind == AccessKind::Write && a.addr == 0xFF80));
+    assert!(log.windows(2).all(|w| w[0].time <= w[1].time));
+
+    let mut bus = ReplayBus::new(log);
+    let mut cpu = cpu();
+    run(&mut cpu, &mut bus);
+
+    assert_eq!(bus.diverged(), None);
+    assert!(bus.is_finished());
+    assert_eq!(cpu.regs(), recorded.regs());
+  }
+
+  #[test]
+  fn replay_notices_a_different_path() {
+    let (_, log) = recording();
+
+    let mut bus = ReplayBus::new(log);
+    let mut cpu = cpu();
+    cpu.regs_mut().hl_mut().set(0xD000);
+    let mut clock = Clock::new(Frequency::Single);
+    cpu.step(&mut bus, &mut clock).unwrap();
+    cpu.step(&mut bus, &mut clock).unwrap();
+
+    // LD A,0x42 and the next fetch match; the store through HL doesn't.
+    assert_eq!(bus.diverged(), Some(3));
+    assert_eq!(bus.read_u8(0x0103), 0xFF);
+    assert!(!bus.is_finished());
+  }
+
+  #[test]
+  fn replay_tells_reads_from_writes() {
+    let log = vec![
+      BusAccess { time: 0, addr: 0xC000, value: 0x12, kind: AccessKind::Write },
+      BusAccess { time: 4, addr: 0xC000, value: 0x12, kind: AccessKind::Read },
+    ];
+
+    let mut bus = ReplayBus::new(log.clone());
+    bus.write_u8(0xC000, 0x12);
+    assert_eq!(bus.read_u8(0xC000), 0x12);
+    assert!(bus.is_finished());
+
+    let mut bus = ReplayBus::new(log.clone());
+    assert_eq!(bus.read_u8(0xC000), 0xFF);
+    assert_eq!(bus.diverged(), Some(0));
+
+    let mut bus = ReplayBus::new(log);
+    bus.write_u8(0xC000, 0x34);
+    assert_eq!(bus.diverged(), Some(0));
+  }
+}
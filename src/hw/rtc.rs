@@ -0,0 +1,186 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use super::cpu::clock::Frequency;
+
+/// Number of clock registers: seconds, minutes, hours, and the day counter's
+/// low and high bytes.
+pub const RTC_REGS: usize = 5;
+
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAY_LOW: usize = 3;
+const DAY_HIGH: usize = 4;
+
+/// Bits of the day-high register.
+const DAY_BIT8: u8 = 0x01;
+const HALT: u8 = 0x40;
+const DAY_CARRY: u8 = 0x80;
+
+/// Bits each register actually has.
+const MASKS: [u8; RTC_REGS] = [0x3F, 0x3F, 0x1F, 0xFF, DAY_CARRY | HALT | DAY_BIT8];
+
+/// The clock's 32.768kHz crystal ticks once a second per this many CPU cycles
+/// at normal speed.
+const CYCLES_PER_SECOND: usize = Frequency::Single.hz() as usize;
+
+/// MBC3's real-time clock. It's driven by emulated cycles rather than the host
+/// clock, so runs stay reproducible.
+///
+/// The game reads a latched copy of the registers, refreshed by writing 0x00
+/// then 0x01 to 0x6000-0x7FFF, while writes go to the running clock.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RTC {
+  live: [u8; RTC_REGS],
+  latched: [u8; RTC_REGS],
+  /// Cycles into the current second.
+  cycles: usize,
+  /// Last value written to the latch register.
+  latch_write: Option<u8>,
+}
+
+impl RTC {
+
+  pub fn new() -> RTC {
+    RTC {
+      live: [0; RTC_REGS],
+      latched: [0; RTC_REGS],
+      cycles: 0,
+      latch_write: None,
+    }
+  }
+
+  /// Reads latched register `reg`, numbered from 0 (seconds) as selected by
+  /// RAM bank values 0x08-0x0C.
+  pub fn read(&self, reg: usize) -> u8 {
+    self.latched.get(reg).cloned().unwrap_or(0xFF)
+  }
+
+  /// Sets running register `reg`. Writing the seconds restarts the current second.
+  pub fn write(&mut self, reg: usize, value: u8) {
+    if reg >= RTC_REGS {
+      return;
+    }
+    self.live[reg] = value & MASKS[reg];
+    if reg == SECONDS {
+      self.cycles = 0;
+    }
+  }
+
+  /// Handles a write to the latch register.
+  pub fn write_latch(&mut self, value: u8) {
+    if self.latch_write == Some(0x00) && value == 0x01 {
+      self.latched = self.live;
+    }
+    self.latch_write = Some(value);
+  }
+
+  /// Advances the running clock by `cycles` CPU cycles, unless halted.
+  pub fn tick(&mut self, cycles: usize) {
+    if self.live[DAY_HIGH] & HALT != 0 {
+      return;
+    }
+    self.cycles += cycles;
+    while self.cycles >= CYCLES_PER_SECOND {
+      self.cycles -= CYCLES_PER_SECOND;
+      self.advance_second();
+    }
+  }
+
+  /// The running and latched registers, for storing next to save RAM.
+  pub fn to_bytes(&self) -> [u8; 2 * RTC_REGS] {
+    let mut bytes = [0; 2 * RTC_REGS];
+    bytes[.. RTC_REGS].copy_from_slice(&self.live);
+    bytes[RTC_REGS ..].copy_from_slice(&self.latched);
+    bytes
+  }
+
+  /// Rebuilds a clock from `to_bytes` output.
+  pub fn from_bytes(bytes: &[u8]) -> Option<RTC> {
+    if bytes.len() != 2 * RTC_REGS {
+      return None;
+    }
+    let mut rtc = RTC::new();
+    for reg in 0 .. RTC_REGS {
+      rtc.live[reg] = bytes[reg] & MASKS[reg];
+      rtc.latched[reg] = bytes[RTC_REGS + reg] & MASKS[reg];
+    }
+    Some(rtc)
+  }
+
+  /// Counters wrap at their natural limit, but one set out of range runs on
+  /// until its bits overflow, without carrying, as on hardware.
+  fn advance_second(&mut self) {
+    if !step(&mut self.live[SECONDS], 60, MASKS[SECONDS])
+      || !step(&mut self.live[MINUTES], 60, MASKS[MINUTES])
+      || !step(&mut self.live[HOURS], 24, MASKS[HOURS]) {
+      return;
+    }
+
+    let high = self.live[DAY_HIGH];
+    let day = ((high & DAY_BIT8) as u16) << 8 | self.live[DAY_LOW] as u16;
+    let day = day + 1;
+    self.live[DAY_LOW] = day as u8;
+    self.live[DAY_HIGH] = match day {
+      0x200 => high & !DAY_BIT8 | DAY_CARRY,
+      _ => high & !DAY_BIT8 | (day >> 8) as u8,
+    };
+  }
+
+}
+
+/// Increments `reg`, returning true if it reached `limit` and carried.
+fn step(reg: &mut u8, limit: u8, mask: u8) -> bool {
+  *reg = reg.wrapping_add(1) & mask;
+  if *reg == limit {
+    *reg = 0;
+    true
+  } else {
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn bytes_round_trip_both_register_sets() {
+    let mut rtc = RTC::new();
+    rtc.write(MINUTES, 12);
+    rtc.write_latch(0x00);
+    rtc.write_latch(0x01);
+    rtc.write(HOURS, 5);
+
+    let restored = RTC::from_bytes(&rtc.to_bytes()).unwrap();
+    assert_eq!(restored.read(MINUTES), 12);
+    assert_eq!(restored.read(HOURS), 0);
+    assert_eq!(restored.to_bytes(), rtc.to_bytes());
+    assert_eq!(RTC::from_bytes(&[0; RTC_REGS]), None);
+  }
+
+  #[test]
+  fn halted_clock_doesnt_run() {
+    let mut rtc = RTC::new();
+    rtc.write(DAY_HIGH, HALT);
+    rtc.tick(10 * CYCLES_PER_SECOND);
+    rtc.write_latch(0x00);
+    rtc.write_latch(0x01);
+    assert_eq!(rtc.read(SECONDS), 0);
+  }
+}
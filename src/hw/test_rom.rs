@@ -0,0 +1,116 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+use super::cart::Cartridge;
+use super::cpu::{Processor, StepErr};
+use super::cpu::clock::Clock;
+use super::mmu::MMU;
+
+const PASSED: &[u8] = b"Passed";
+const FAILED: &[u8] = b"Failed";
+
+#[derive(Debug)]
+pub enum TestResult {
+  Passed,
+  Failed,
+  /// `max_cycles` elapsed without a verdict on the serial port.
+  Timeout,
+  /// The CPU stopped on an instruction it couldn't execute.
+  Errored(StepErr),
+}
+
+/// Runs a test ROM that reports its verdict over the serial port, as Blargg's
+/// do, for at most `max_cycles` T-cycles.
+pub fn run_test_rom(cart: Cartridge, max_cycles: usize) -> TestResult {
+  let mut cpu = Processor::new();
+  cpu.skip_boot(&cart);
+  let mut mmu = MMU::new(cart);
+  let mut clock = Clock::for_model(mmu.model());
+  let mut seen = 0;
+
+  while clock.time() < max_cycles {
+    if let Err(e) = cpu.step(&mut mmu, &mut clock) {
+      return TestResult::Errored(e);
+    }
+
+    let output = mmu.serial_output();
+    if output.len() != seen {
+      seen = output.len();
+      if contains(output, PASSED) {
+        return TestResult::Passed;
+      }
+      if contains(output, FAILED) {
+        return TestResult::Failed;
+      }
+    }
+  }
+
+  TestResult::Timeout
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+  haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+  use alloc::vec::Vec;
+
+  use hw::cart::tests::rom;
+  use super::*;
+
+  /// A cartridge that prints `message` over the serial port, then spins.
+  fn printing(message: &[u8]) -> Cartridge {
+    let mut program = Vec::new();
+    for &c in message.iter() {
+      // LD A,c; LDH (SB),A; LD A,0x81; LDH (SC),A
+      program.extend_from_slice(&[0x3E, c, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]);
+    }
+    // JR -2
+    program.extend_from_slice(&[0x18, 0xFE]);
+
+    let mut bytes = rom("SERIAL", 0x00, 0x00, 0x00);
+    // JR 0x0150, past the header
+    bytes[0x101 .. 0x103].copy_from_slice(&[0x18, 0x4D]);
+    bytes[0x150 .. 0x150 + program.len()].copy_from_slice(&program);
+    Cartridge::new(bytes).unwrap()
+  }
+
+  #[test]
+  fn passes_when_serial_says_passed() {
+    match run_test_rom(printing(b"cpu_instrs\nPassed\n"), 100_000) {
+      TestResult::Passed => {},
+      x => panic!("expected Passed, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn fails_when_serial_says_failed() {
+    match run_test_rom(printing(b"Failed #3"), 100_000) {
+      TestResult::Failed => {},
+      x => panic!("expected Failed, got {:?}", x),
+    }
+  }
+
+  #[test]
+  fn times_out_without_a_verdict() {
+    match run_test_rom(printing(b"Pass"), 100_000) {
+      TestResult::Timeout => {},
+      x => panic!("expected Timeout, got {:?}", x),
+    }
+  }
+}
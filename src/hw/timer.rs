@@ -0,0 +1,154 @@
+// Copyright (c) 2018 Brett Russell
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+const REG_DIV: u16 = 0xFF04;
+const REG_TIMA: u16 = 0xFF05;
+const REG_TMA: u16 = 0xFF06;
+const REG_TAC: u16 = 0xFF07;
+
+/// DIV, TIMA, TMA and TAC. Both DIV and TIMA are driven by one 16-bit counter
+/// that ticks every T-cycle: DIV is its upper byte, and TIMA counts falling
+/// edges of the counter bit TAC selects, gated by TAC's enable bit.
+#[derive(Clone, Debug, Default)]
+pub struct Timer {
+  counter: u16,
+  tima: u8,
+  tma: u8,
+  tac: u8,
+}
+
+impl Timer {
+
+  pub fn new() -> Timer {
+    Timer::default()
+  }
+
+  /// The full internal counter, of which DIV shows the upper byte.
+  pub fn counter(&self) -> u16 {
+    self.counter
+  }
+
+  pub fn div(&self) -> u8 {
+    (self.counter >> 8) as u8
+  }
+
+  /// Reads one of the timer registers at 0xFF04-0xFF07.
+  pub fn read(&self, addr: u16) -> u8 {
+    match addr {
+      REG_DIV => self.div(),
+      REG_TIMA => self.tima,
+      REG_TMA => self.tma,
+      // The unused upper bits read high.
+      REG_TAC => 0xF8 | self.tac,
+      _ => 0xFF,
+    }
+  }
+
+  /// Writes one of the timer registers at 0xFF04-0xFF07. Returns true if the
+  /// write made TIMA overflow, which requests the timer interrupt.
+  ///
+  /// Writing DIV clears the whole counter, and writing TAC can move the
+  /// selected bit; either way a 1 falling to 0 bumps TIMA as a real tick would.
+  pub fn write(&mut self, addr: u16, value: u8) -> bool {
+    match addr {
+      REG_DIV => self.update(|t| t.counter = 0),
+      REG_TIMA => { self.tima = value; false },
+      REG_TMA => { self.tma = value; false },
+      REG_TAC => self.update(|t| t.tac = value & 0x07),
+      _ => false,
+    }
+  }
+
+  /// Advances by `cycles` T-cycles. Returns true if TIMA overflowed.
+  pub fn tick(&mut self, cycles: usize) -> bool {
+    let mut overflowed = false;
+    for _ in 0..cycles {
+      overflowed |= self.update(|t| t.counter = t.counter.wrapping_add(1));
+    }
+    overflowed
+  }
+
+  /// The input TIMA counts falling edges of.
+  fn signal(&self) -> bool {
+    let bit = match self.tac & 0x03 {
+      0 => 9,
+      1 => 3,
+      2 => 5,
+      _ => 7,
+    };
+    self.tac & 0x04 != 0 && self.counter & (1 << bit) != 0
+  }
+
+  /// Applies `change`, then increments TIMA if the signal fell. Returns true on
+  /// overflow, when TIMA reloads from TMA.
+  fn update<F: FnOnce(&mut Timer)>(&mut self, change: F) -> bool {
+    let before = self.signal();
+    change(self);
+    if !before || self.signal() {
+      return false;
+    }
+    let (tima, overflowed) = self.tima.overflowing_add(1);
+    self.tima = if overflowed { self.tma } else { tima };
+    overflowed
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn div_is_the_upper_byte_of_the_counter() {
+    let mut timer = Timer::new();
+    timer.tick(0x1FF);
+    assert_eq!(timer.counter(), 0x01FF);
+    assert_eq!(timer.read(REG_DIV), 0x01);
+
+    timer.write(REG_DIV, 0x77);
+    assert_eq!(timer.counter(), 0);
+  }
+
+  #[test]
+  fn resetting_div_with_the_selected_bit_high_ticks_tima() {
+    let mut timer = Timer::new();
+    // Enabled, counting bit 3 (every 16 T-cycles).
+    timer.write(REG_TAC, 0x05);
+    timer.tick(8);
+    assert_eq!(timer.read(REG_TIMA), 0);
+
+    timer.write(REG_DIV, 0x00);
+    assert_eq!(timer.read(REG_TIMA), 1);
+
+    // With the bit low, the reset doesn't count.
+    timer.tick(4);
+    timer.write(REG_DIV, 0x00);
+    assert_eq!(timer.read(REG_TIMA), 1);
+  }
+
+  #[test]
+  fn tima_overflow_reloads_from_tma() {
+    let mut timer = Timer::new();
+    timer.write(REG_TMA, 0xF0);
+    timer.write(REG_TIMA, 0xFF);
+    timer.write(REG_TAC, 0x05);
+
+    assert!(!timer.tick(15));
+    assert!(timer.tick(1));
+    assert_eq!(timer.read(REG_TIMA), 0xF0);
+  }
+}
@@ -15,22 +15,38 @@
 // DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
-#![feature(try_from)]
+extern crate gbers;
 
-mod hw;
+use gbers::hw;
+use gbers::hw::cpu::Processor;
+use gbers::hw::cpu::clock::{Clock, Frequency};
+use gbers::hw::mmu::MMU;
+
+/// How long to run the cartridge for, in T-cycles (about one second).
+const RUN_CYCLES: usize = Frequency::Single.hz() as usize;
 
 fn main() {
   let c = hw::cart::Cartridge::from_file("pky.gbc");
 
   match c {
     Ok(y) => {
-      println!("Title: {}", y.title());
+      let info = y.describe();
+      println!("Title: {}", info.title);
       println!("COMPONENTS LIST:");
-      for comp in y.components() {
+      for comp in &info.components {
         println!("  {:?}", comp);
       }
-      println!("Is CGB: {}", y.is_cgb());
-      println!("Is SGB: {}", y.is_sgb());
+      println!("Is CGB: {}", info.is_cgb);
+      println!("Is SGB: {}", info.is_sgb);
+
+      let mut cpu = Processor::new();
+      cpu.skip_boot(&y);
+      let mut mmu = MMU::new(y);
+      let mut clock = Clock::for_model(mmu.model());
+      match cpu.start(&mut mmu, &mut clock, Some(RUN_CYCLES)) {
+        Ok(()) => println!("Ran to {} cycles", clock.time()),
+        Err(e) => println!("{:?}", e),
+      }
     },
     Err(y) => println!("{:?}", y),
   }